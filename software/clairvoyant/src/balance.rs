@@ -0,0 +1,82 @@
+use regex::Regex;
+
+use crate::config::BalanceConfig;
+
+/// Extracts the remaining credit from the USSD replies to a balance query
+pub struct BalanceMonitor {
+    pattern: Regex,
+    threshold: Option<f64>,
+    /// Whether the last balance was below the threshold, so that it is only alerted on once
+    low: bool,
+}
+
+impl BalanceMonitor {
+    pub fn new(config: &BalanceConfig) -> Result<Self, regex::Error> {
+        Ok(BalanceMonitor {
+            pattern: Regex::new(&config.pattern)?,
+            threshold: config.threshold,
+            low: false,
+        })
+    }
+
+    /// Returns the balance from the first capture group of the pattern, accepting a decimal comma
+    pub fn extract(&self, reply: &str) -> Option<f64> {
+        let captures = self.pattern.captures(reply)?;
+        captures
+            .get(1)?
+            .as_str()
+            .trim()
+            .replace(',', ".")
+            .parse()
+            .ok()
+    }
+
+    pub fn is_low(&self, balance: f64) -> bool {
+        match self.threshold {
+            Some(threshold) => balance < threshold,
+            None => false,
+        }
+    }
+
+    /// Whether `balance` has just dropped below the threshold. It stays quiet while the balance
+    /// stays low, until it has been topped up again.
+    pub fn dropped_low(&mut self, balance: f64) -> bool {
+        let was_low = self.low;
+        self.low = self.is_low(balance);
+        self.low && !was_low
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn monitor(threshold: &str) -> BalanceMonitor {
+        let config = format!(
+            "code = \"*101#\"\npattern = 'balance is ([0-9.,]+) EUR'\n{}",
+            threshold
+        );
+        BalanceMonitor::new(&toml::from_str(&config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_extract() {
+        let monitor = monitor("");
+        assert_eq!(monitor.extract("Your balance is 12.50 EUR."), Some(12.5));
+        assert_eq!(monitor.extract("Your balance is 3,20 EUR."), Some(3.2));
+        assert_eq!(monitor.extract("Service unavailable"), None);
+        assert!(!monitor.is_low(0.0));
+    }
+
+    #[test]
+    fn test_dropped_low() {
+        let mut monitor = monitor("threshold = 5.0");
+        assert!(!monitor.is_low(5.0));
+        assert!(monitor.is_low(4.99));
+        assert!(!monitor.dropped_low(10.0));
+        assert!(monitor.dropped_low(4.0));
+        assert!(!monitor.dropped_low(3.0));
+        assert!(!monitor.dropped_low(20.0));
+        assert!(monitor.dropped_low(1.0));
+    }
+}
@@ -0,0 +1,423 @@
+//! A simulated SIM800 modem, for exercising the daemon without hardware.
+//!
+//! Attach it to the daemon's PTY transport:
+//!
+//! ```text
+//! sim800 /run/zuul/modem door.script
+//! ```
+//!
+//! The simulator answers AT commands the way the SIM800 firmware does, and in parallel plays a
+//! script, one step per line (`#` at the start of a word starts a comment):
+//!
+//! * `send <text>`: send a line, e.g. a URC
+//! * `expect <text> [<secs>]`: wait until a command containing `<text>` arrives (default 30s)
+//! * `sleep <secs>`
+//! * `register [<stat>]`: report the registration state (default 1, registered)
+//! * `ring <number> [<validity>]`: an incoming call, as `RING` followed by `+CLIP` with the given
+//!   CLI validity (default 0, valid)
+//! * `withheld`: an incoming call with the number withheld
+//! * `hangup`: the caller hangs up
+//! * `dtmf <keys>`: the caller presses keys, reported once DTMF detection is enabled
+//! * `pin <code>|none`: the PIN the SIM asks for after the next boot (default 1111)
+//! * `ussd <reply>`: the reply to USSD requests
+//! * `garbage <bytes>`: line noise
+//! * `silence <secs>`: ignore all commands for a while, so that they time out
+//! * `reset`: an unexpected reboot
+//!
+//! The simulator exits with an error if an `expect` times out, and successfully at the end of the
+//! script.
+//!
+//! It is only built with the `simulator` feature, which the door tests need, so that it doesn't
+//! end up installed next to the daemon.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{bail, format_err, Error};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+struct Options {
+    /// The TTY the daemon listens on
+    port: PathBuf,
+    /// The script to play
+    script: PathBuf,
+}
+
+struct State {
+    /// The PIN asked for at boot, if any
+    pin: Option<String>,
+    unlocked: bool,
+    echo: bool,
+    creg_urc: u8,
+    /// Kept in NVRAM, so it survives a reboot
+    clts: u8,
+    regstate: u8,
+    ussd_reply: String,
+    silent_until: Option<Instant>,
+    /// Every command received so far
+    received: Vec<String>,
+}
+
+struct Sim {
+    port: Mutex<File>,
+    state: Mutex<State>,
+    command_received: Condvar,
+}
+
+impl Sim {
+    fn send(&self, text: &str) {
+        let mut port = self.port.lock().unwrap();
+        port.write_all(format!("\r\n{}\r\n", text).as_bytes())
+            .expect("Failed to write to port");
+    }
+
+    fn boot(&self) {
+        let cpin = {
+            let mut state = self.state.lock().unwrap();
+            state.unlocked = state.pin.is_none();
+            state.echo = true;
+            state.creg_urc = 0;
+            state.regstate = 0;
+            if state.unlocked {
+                "READY"
+            } else {
+                "SIM PIN"
+            }
+        };
+        self.send("RDY");
+        self.send("+CFUN: 1");
+        self.send(&format!("+CPIN: {}", cpin));
+    }
+
+    /// Read commands from the daemon and answer them, until the port is closed
+    fn respond(self: Arc<Self>, port: File) {
+        for line in BufReader::new(port).split(b'\n') {
+            let line = match line {
+                Ok(line) => String::from_utf8_lossy(&line).trim().to_owned(),
+                Err(_) => return,
+            };
+            if line.is_empty() {
+                continue;
+            }
+            let echo = {
+                let mut state = self.state.lock().unwrap();
+                state.received.push(line.clone());
+                self.command_received.notify_all();
+                if matches!(state.silent_until, Some(until) if until > Instant::now()) {
+                    continue;
+                }
+                state.echo
+            };
+            if echo {
+                self.port
+                    .lock()
+                    .unwrap()
+                    .write_all(format!("{}\r\n", line).as_bytes())
+                    .ok();
+            }
+            self.execute(&line);
+        }
+    }
+
+    /// Run a command line, which may chain several commands, e.g. `ATE1+CREG=1;+CLIP=1`
+    fn execute(&self, line: &str) {
+        let upper = line.to_uppercase();
+        if !upper.starts_with("AT") {
+            self.send("ERROR");
+            return;
+        }
+        if upper.contains("+CPOWD=1") {
+            // There is no power pin to pull, so power down means reboot
+            self.send("NORMAL POWER DOWN");
+            thread::sleep(Duration::from_millis(500));
+            self.boot();
+            return;
+        }
+        let mut info = Vec::new();
+        let mut after = Vec::new();
+        for command in split_commands(&line[2..]) {
+            match self.command(&command, &mut info, &mut after) {
+                Ok(()) => {}
+                Err(error) => {
+                    self.send(&error);
+                    return;
+                }
+            }
+        }
+        for line in info {
+            self.send(&line);
+        }
+        self.send("OK");
+        for line in after {
+            self.send(&line);
+        }
+    }
+
+    /// Run a single command. Information text goes in `info`; URCs it provokes go in `after`.
+    fn command(
+        &self,
+        command: &str,
+        info: &mut Vec<String>,
+        after: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let upper = command.to_uppercase();
+        let mut state = self.state.lock().unwrap();
+        let (name, arg) = match upper.find(&['=', '?'][..]) {
+            Some(pos) => (&upper[..pos], &command[pos..]),
+            None => (upper.as_str(), ""),
+        };
+        match (name, arg) {
+            ("E0", _) => state.echo = false,
+            ("E1", _) | ("E", _) => state.echo = true,
+            ("A", _) | ("H", _) | ("H0", _) | ("Z", _) | ("&W", _) => {}
+            (_, _) if name.starts_with('D') => {}
+            (_, _) if name.starts_with('Q') || name.starts_with('V') => {}
+            ("+CGMI", "") => info.push("SIMCOM_Ltd".to_owned()),
+            ("+CGMM", "") => info.push("SIMCOM_SIM800L".to_owned()),
+            ("+CGMR", "") => info.push("Revision:1418B05SIM800L24".to_owned()),
+            ("+CGSN", "") => info.push("861234567890123".to_owned()),
+            ("+CIMI", "") if state.unlocked => info.push("206011234567890".to_owned()),
+            ("+CCID", "") => info.push("8932011234567890123F".to_owned()),
+            ("+CPIN", "?") => info.push(format!(
+                "+CPIN: {}",
+                if state.unlocked { "READY" } else { "SIM PIN" }
+            )),
+            ("+CPIN", _) => {
+                let pin = arg[1..].trim_matches('"');
+                if state.pin.as_deref() != Some(pin) {
+                    return Err("+CME ERROR: incorrect password".to_owned());
+                }
+                state.unlocked = true;
+                after.push("+CPIN: READY".to_owned());
+                after.push("Call Ready".to_owned());
+                after.push("SMS Ready".to_owned());
+            }
+            ("+CREG", "?") => info.push(format!("+CREG: {},{}", state.creg_urc, state.regstate)),
+            ("+CREG", _) => state.creg_urc = arg[1..].parse().map_err(|_| "ERROR")?,
+            ("+CSQ", "") => info.push("+CSQ: 18,0".to_owned()),
+            ("+COPS", "?") => info.push("+COPS: 0,0,\"BASE\"".to_owned()),
+            ("+CCLK", "?") => {
+                let now = chrono::Local::now();
+                let zone = now.offset().local_minus_utc() / (15 * 60);
+                info.push(format!(
+                    "+CCLK: \"{}{:+03}\"",
+                    now.format("%y/%m/%d,%H:%M:%S"),
+                    zone
+                ));
+            }
+            ("+CUSD", _) if arg.starts_with("=1") => {
+                if state.regstate != 1 && state.regstate != 5 {
+                    return Err("+CME ERROR: no network service".to_owned());
+                }
+                after.push(format!("+CUSD: 0,\"{}\",15", state.ussd_reply));
+            }
+            ("+CLTS", "?") => info.push(format!("+CLTS: {}", state.clts)),
+            ("+CLTS", _) => state.clts = arg[1..].parse().map_err(|_| "ERROR")?,
+            ("+CLIP", _) | ("+CSCS", _) | ("+CFUN", _) | ("+CUSD", _) => {}
+            ("+VTS", _) | ("+DDET", _) | ("+CMEE", _) => {}
+            _ => return Err("ERROR".to_owned()),
+        }
+        Ok(())
+    }
+
+    /// Wait until a command containing `text` arrives, after the first `seen` commands
+    fn expect(&self, text: &str, seen: &mut usize, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(pos) = state.received[*seen..]
+                .iter()
+                .position(|command| command.contains(text))
+            {
+                *seen += pos + 1;
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                bail!("Timed out waiting for {:?}", text);
+            }
+            state = self
+                .command_received
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+/// Split the part of a command line after `AT` into single commands
+fn split_commands(line: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    for segment in line.split(';') {
+        let mut rest = segment.trim();
+        // Basic commands (a letter, or & and a letter, with an optional number) come first
+        while !rest.is_empty() && !rest.starts_with('+') {
+            let prefix = if rest.starts_with('&') { 2 } else { 1 };
+            // In bytes; a driver bug may send anything, and slicing must not panic on it
+            let prefix = rest
+                .char_indices()
+                .nth(prefix)
+                .map_or(rest.len(), |(pos, _)| pos);
+            let len = prefix
+                + rest[prefix..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - prefix);
+            if rest[..prefix].to_uppercase().starts_with('D') {
+                // Dial strings run to the end of the segment
+                commands.push(rest.to_owned());
+                rest = "";
+            } else {
+                commands.push(rest[..len].to_owned());
+                rest = &rest[len..];
+            }
+        }
+        if !rest.is_empty() {
+            commands.push(rest.to_owned());
+        }
+    }
+    commands
+}
+
+fn run_script(sim: &Sim, script: &str) -> Result<(), Error> {
+    let mut seen = 0;
+    // A fixed-seed generator, so that garbage is the same from run to run
+    let mut noise: u32 = 0x2545_F491;
+    for (lineno, line) in script.lines().enumerate() {
+        let comment = line
+            .char_indices()
+            .find(|&(pos, c)| c == '#' && (pos == 0 || line[..pos].ends_with(char::is_whitespace)))
+            .map_or(line.len(), |(pos, _)| pos);
+        let line = line[..comment].trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (step, arg) = match line.find(' ') {
+            Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+            None => (line, ""),
+        };
+        let secs = |arg: &str| -> Result<Duration, Error> {
+            let secs: f64 = arg
+                .parse()
+                .map_err(|_| format_err!("line {}: bad duration {:?}", lineno + 1, arg))?;
+            Ok(Duration::from_millis((secs * 1000.) as u64))
+        };
+        match step {
+            "send" => sim.send(arg),
+            "expect" => {
+                let (text, timeout) = match arg.rfind(' ') {
+                    Some(pos) if arg[pos + 1..].parse::<f64>().is_ok() => {
+                        (&arg[..pos], secs(&arg[pos + 1..])?)
+                    }
+                    _ => (arg, Duration::from_secs(30)),
+                };
+                sim.expect(text, &mut seen, timeout)
+                    .map_err(|err| format_err!("line {}: {}", lineno + 1, err))?;
+            }
+            "sleep" => thread::sleep(secs(arg)?),
+            "register" => {
+                let regstate = if arg.is_empty() { 1 } else { arg.parse()? };
+                let report = {
+                    let mut state = sim.state.lock().unwrap();
+                    state.regstate = regstate;
+                    state.creg_urc > 0
+                };
+                if report {
+                    sim.send(&format!("+CREG: {}", regstate));
+                }
+            }
+            "ring" => {
+                let (number, validity) = match arg.find(' ') {
+                    Some(pos) => (&arg[..pos], arg[pos + 1..].parse::<u8>()?),
+                    None => (arg, 0),
+                };
+                sim.send("RING");
+                sim.send(&format!(
+                    "+CLIP: \"{}\",145,\"\",0,\"\",{}",
+                    number, validity
+                ));
+            }
+            "withheld" => {
+                sim.send("RING");
+                sim.send("+CLIP: \"\",128,\"\",0,\"\",1");
+            }
+            "hangup" => sim.send("NO CARRIER"),
+            "dtmf" => {
+                for key in arg.chars() {
+                    sim.send(&format!("+DTMF: {}", key));
+                }
+            }
+            "pin" => {
+                sim.state.lock().unwrap().pin = match arg {
+                    "none" => None,
+                    pin => Some(pin.to_owned()),
+                }
+            }
+            "ussd" => sim.state.lock().unwrap().ussd_reply = arg.to_owned(),
+            "garbage" => {
+                let mut garbage = Vec::new();
+                for _ in 0..arg.parse::<usize>()? {
+                    noise ^= noise << 13;
+                    noise ^= noise >> 17;
+                    noise ^= noise << 5;
+                    garbage.push(noise as u8);
+                }
+                sim.port.lock().unwrap().write_all(&garbage)?;
+            }
+            "silence" => sim.state.lock().unwrap().silent_until = Some(Instant::now() + secs(arg)?),
+            "reset" => sim.boot(),
+            _ => bail!("line {}: unknown step {:?}", lineno + 1, step),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    let options: Options = StructOpt::from_args();
+    let script = std::fs::read_to_string(&options.script)?;
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&options.port)?;
+
+    let sim = Arc::new(Sim {
+        port: Mutex::new(port.try_clone()?),
+        state: Mutex::new(State {
+            pin: Some("1111".to_owned()),
+            unlocked: false,
+            echo: true,
+            creg_urc: 0,
+            clts: 0,
+            regstate: 0,
+            ussd_reply: "Uw saldo is 12,34 \u{1B}e".to_owned(),
+            silent_until: None,
+            received: Vec::new(),
+        }),
+        command_received: Condvar::new(),
+    });
+
+    let responder = sim.clone();
+    thread::spawn(move || responder.respond(port));
+
+    run_script(&sim, &script)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_commands() {
+        assert_eq!(
+            split_commands("E1&W+CREG=1;+CLIP=1"),
+            vec!["E1", "&W", "+CREG=1", "+CLIP=1"]
+        );
+        assert_eq!(split_commands("D*101#"), vec!["D*101#"]);
+        assert_eq!(split_commands("é&é+CSQ"), vec!["é", "&é", "+CSQ"]);
+        assert_eq!(split_commands("&"), vec!["&"]);
+    }
+}
@@ -0,0 +1,131 @@
+//! Talks to a running daemon through its control socket.
+//!
+//! ```text
+//! zuul at AT+CSQ AT+COPS?
+//! zuul at          # one command per line from stdin
+//! zuul pending
+//! zuul approve 32470000002 Bob --until 2019-12-31
+//! ```
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use failure::{bail, Error};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+struct Options {
+    /// The daemon's control socket
+    #[structopt(short = "S", long = "socket", default_value = "/run/zuul/control.sock")]
+    socket: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Send AT commands to the modem, and show the responses
+    #[structopt(name = "at")]
+    At {
+        /// Commands to send; read from stdin if none are given
+        commands: Vec<String>,
+    },
+    /// List the unknown callers waiting for approval
+    #[structopt(name = "pending")]
+    Pending,
+    /// Add a whitelist rule for a pending caller
+    #[structopt(name = "approve")]
+    Approve {
+        /// The caller's number, or `#<id>` as listed
+        caller: String,
+        /// Letters and digits only
+        label: String,
+        /// Last day the rule applies, e.g. 2019-12-31
+        #[structopt(long = "until")]
+        until: Option<String>,
+    },
+    /// Forget about a pending caller
+    #[structopt(name = "dismiss")]
+    Dismiss {
+        /// The caller's number, or `#<id>` as listed
+        caller: String,
+    },
+}
+
+/// Whether `line` ends the reply to a request
+fn is_final(line: &str) -> bool {
+    line == "OK"
+        || line.starts_with("ERROR")
+        || line.starts_with("+CME ERROR")
+        || line.starts_with("+CMS ERROR")
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Connection {
+    /// Send a request and print the reply, returning whether it succeeded
+    fn request(&mut self, request: &str) -> Result<bool, Error> {
+        writeln!(self.writer, "{}", request)?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                bail!("Connection closed by the daemon");
+            }
+            let line = line.trim_end();
+            println!("{}", line);
+            if is_final(line) {
+                return Ok(line == "OK");
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let options: Options = StructOpt::from_args();
+    let stream = UnixStream::connect(&options.socket)?;
+    let mut connection = Connection {
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+    };
+
+    let request = match options.command {
+        Command::At { commands } => {
+            let mut ok = true;
+            if commands.is_empty() {
+                for line in io::stdin().lock().lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        connection.request(&format!("at {}", line.trim()))?;
+                    }
+                }
+            } else {
+                for command in commands {
+                    ok &= connection.request(&format!("at {}", command))?;
+                }
+            }
+            if !ok {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::Pending => "pending".to_owned(),
+        Command::Approve {
+            caller,
+            label,
+            until,
+        } => match until {
+            Some(until) => format!("approve {} {} {}", caller, label, until),
+            None => format!("approve {} {}", caller, label),
+        },
+        Command::Dismiss { caller } => format!("dismiss {}", caller),
+    };
+    if !connection.request(&request)? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use chrono::{DateTime, Duration, FixedOffset, Local, Utc};
+use serde::Deserialize;
+
+/// Anything before 2019-01-01 means the Pi booted without RTC or network, and is still counting
+/// from the epoch
+const MIN_PLAUSIBLE_TIMESTAMP: i64 = 1_546_300_800;
+
+/// How far the system clock may drift from the network time before we stop trusting it
+const MAX_SKEW_SECS: i64 = 30;
+
+/// How time-based whitelist rules are evaluated while the current time is unknown
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownTimePolicy {
+    /// Time-based components match regardless of the time
+    FailOpen,
+    /// Time-based components never match
+    FailClosed,
+}
+
+// Deriving it takes #[default], which needs Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for UnknownTimePolicy {
+    fn default() -> Self {
+        UnknownTimePolicy::FailClosed
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The system clock is plausible and no network time contradicts it
+    System,
+    /// The system clock is off, and times are corrected using the network time
+    Network,
+    Unknown,
+}
+
+/// Tracks whether the system clock can be believed, using the network time (NITZ) reported by the
+/// modem as a second opinion
+#[derive(Default)]
+pub struct Clock {
+    network_offset: Option<Duration>,
+}
+
+impl Clock {
+    /// Record the time reported by the network, returning how far the system clock is off
+    pub fn set_network_time(&mut self, time: DateTime<FixedOffset>) -> Duration {
+        let offset = time.with_timezone(&Utc) - Utc::now();
+        self.network_offset = Some(offset);
+        offset
+    }
+
+    pub fn source(&self) -> TimeSource {
+        self.source_at(Utc::now())
+    }
+
+    /// The current local time, or None if neither the system nor the network can tell us
+    pub fn now(&self) -> Option<DateTime<Local>> {
+        self.now_at(Local::now())
+    }
+
+    /// The source of the time, given what the system clock says
+    fn source_at(&self, system: DateTime<Utc>) -> TimeSource {
+        match self.network_offset {
+            Some(offset) if offset.num_seconds().abs() <= MAX_SKEW_SECS => TimeSource::System,
+            Some(_) => TimeSource::Network,
+            None if system.timestamp() >= MIN_PLAUSIBLE_TIMESTAMP => TimeSource::System,
+            None => TimeSource::Unknown,
+        }
+    }
+
+    fn now_at(&self, system: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self.source_at(system.with_timezone(&Utc)) {
+            TimeSource::System => Some(system),
+            TimeSource::Network => self.network_offset.map(|offset| system + offset),
+            TimeSource::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_source() {
+        let booted = Local.timestamp(86_400, 0);
+        let current = Local.ymd(2019, 9, 2).and_hms(12, 0, 0);
+        let mut clock = Clock::default();
+        assert_eq!(
+            clock.source_at(booted.with_timezone(&Utc)),
+            TimeSource::Unknown
+        );
+        assert_eq!(clock.now_at(booted), None);
+        assert_eq!(clock.now_at(current), Some(current));
+
+        // The network vouches for the system clock
+        clock.network_offset = Some(Duration::seconds(MAX_SKEW_SECS));
+        assert_eq!(clock.now_at(current), Some(current));
+        assert_eq!(
+            clock.source_at(booted.with_timezone(&Utc)),
+            TimeSource::System
+        );
+
+        // Or corrects it
+        clock.network_offset = Some(current - booted);
+        assert_eq!(
+            clock.source_at(booted.with_timezone(&Utc)),
+            TimeSource::Network
+        );
+        assert_eq!(clock.now_at(booted), Some(current));
+    }
+
+    #[test]
+    fn test_set_network_time() {
+        let mut clock = Clock::default();
+        let offset = clock.set_network_time((Utc::now() + Duration::hours(1)).into());
+        assert!((offset - Duration::hours(1)).num_seconds().abs() <= 1);
+        assert_eq!(clock.source(), TimeSource::Network);
+        clock.set_network_time(Utc::now().into());
+        assert_eq!(clock.source(), TimeSource::System);
+    }
+}
@@ -1,49 +1,640 @@
+use crate::clock::UnknownTimePolicy;
+use crate::event::CliValidity;
+use crate::modem::transport::TransportConfig;
+use crate::privacy::NumberDisplay;
+use crate::report::EVENTS;
+use crate::whitelist::Decision;
+use failure::{bail, Error};
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct SocketPaths {
-    // Default: inproc://modem_urc
-    modem_urc: Option<String>,
-    // Default: inproc://modem_rpc
-    modem_rpc: Option<String>,
-    // Default: inproc://event
-    event: Option<String>,
+    /// Unix socket for `zuul` to talk to the daemon through. Default: /run/zuul/control.sock
+    control: Option<PathBuf>,
 }
 
+/// The broker to publish to; `--mqtt-server` takes precedence over `server` and `port`
 #[derive(Clone, Debug, Deserialize)]
 pub struct MqttConfig {
     pub enable: bool,
     pub server: String,
     pub port: u16,
     pub client_id: String,
+    pub username: Option<String>,
+    password: Option<String>,
+    /// File holding the password, so that the config can be world-readable
+    password_file: Option<PathBuf>,
+    /// Connect over TLS
+    pub tls: Option<TlsConfig>,
+    /// Event types to publish, e.g. `["open", "denied"]`. Default: all of them
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// PEM bundle of the CAs the broker's certificate is checked against. Default: the system's
+    pub ca: Option<PathBuf>,
+    /// PEM client certificate, for brokers that authenticate clients by certificate
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`
+    pub key: Option<PathBuf>,
+    /// OpenSSL cipher list, e.g. `HIGH:!aNULL`. Default: OpenSSL's
+    pub ciphers: Option<String>,
+    /// Check the broker's certificate against `ca`. Default: true
+    verify: Option<bool>,
+    /// Check that the broker's certificate names the configured server. Default: same as `verify`
+    verify_hostname: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SimConfig {
+    /// Sent once if the SIM asks for it. A SIM that rejects it, or wants its PUK, is left alone.
+    pub pin: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BalanceConfig {
+    /// USSD code that asks the network for the remaining credit, e.g. `*101#`
+    pub code: String,
+    /// Regex applied to the decoded reply. The first capture group is the balance.
+    pub pattern: String,
+    /// Alert when the balance drops below this amount
+    pub threshold: Option<f64>,
+    // Default: 86400 (once a day), in seconds
+    interval: Option<u64>,
+}
+
+/// Lets callers who are not on the whitelist in with a PIN, entered as DTMF
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeypadConfig {
+    // Default: 20, in seconds
+    timeout: Option<u64>,
+    /// Wrong PINs before a caller is locked out. Default: 3
+    max_attempts: Option<u32>,
+    /// Wrong PINs from all callers together before the keypad is shut for everyone, against
+    /// guessing from many numbers. Every further one doubles how long. Default: 10
+    max_attempts_total: Option<u32>,
+    // Default: 900, in seconds
+    lockout: Option<u64>,
+}
+
+/// Limits on how often calls are taken, per number and for all numbers together
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RateLimitConfig {
+    /// Calls a single number can make back to back. Default: 5
+    caller_burst: Option<u32>,
+    // Default: 60, in seconds until a number gets another call
+    caller_refill: Option<u64>,
+    /// Calls all numbers together can make back to back, not counting numbers on the whitelist.
+    /// Default: no limit
+    global_burst: Option<u32>,
+    // Default: 6, in seconds until another call is allowed
+    global_refill: Option<u64>,
+    // Default: 900, in seconds that a number over its limit is turned away
+    lockout: Option<u64>,
+    /// Publish to zuul/alert/rate_limit when a limit is reached. Default: true
+    alert: Option<bool>,
+}
+
+/// Accepts signed commands on `zuul/cmd/+`; see `remote`
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommandsConfig {
+    /// Shared secret the commands are signed with
+    secret: Option<String>,
+    /// File holding the shared secret, so that the config can be world-readable
+    secret_file: Option<PathBuf>,
+    // Default: 60, in seconds either side of our clock
+    max_age: Option<i64>,
+    /// Also run the read-only AT queries sent to `zuul/cmd/at`; see `remote`. Default: false
+    #[serde(default)]
+    pub diagnostics: bool,
+    // Default: /var/lib/zuul/lockdown, which exists while the unit is in lockdown
+    lockdown_state: Option<PathBuf>,
+}
+
+/// Accepts whitelists pushed over MQTT; see `push`
+#[derive(Clone, Debug, Deserialize)]
+pub struct WhitelistPushConfig {
+    /// Names this unit in `zuul/<unit>/whitelist`
+    pub unit: String,
+    /// Ed25519 public key the whitelists are signed with, in hex
+    public_key: String,
+    // Default: /var/lib/zuul/whitelist-push.json
+    state: Option<PathBuf>,
 }
 
-#[derive(Deserialize)]
+/// Notifiers besides MQTT, e.g. `[[notify.webhook]]`; see `notify`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub exec: Vec<ExecConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// Plain HTTP only, e.g. `http://10.0.0.5:1880/zuul`
+    pub url: String,
+    /// Event types to send. Default: all of them
+    #[serde(default)]
+    pub events: Vec<String>,
+    // Default: 5, in seconds
+    timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExecConfig {
+    /// Run with the event in `ZUUL_*` environment variables
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Event types to run it for. Default: all of them
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Publishes Home Assistant MQTT discovery configs for the door unit; see `discovery`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HomeAssistantConfig {
+    // Default: homeassistant
+    prefix: Option<String>,
+    /// Add a button that opens the door through `zuul/ha/open/<open_token>`. Anyone who knows the
+    /// token can open the door, and it is in the retained discovery config, so only let Home
+    /// Assistant read the discovery prefix. Default: false
+    #[serde(default)]
+    pub remote_open: bool,
+    /// At least 16 letters, digits, `-` or `_`; required with `remote_open`
+    open_token: Option<String>,
+}
+
+/// Keeps events on disk while the broker is unreachable, and sends them once it is back
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct OutboxConfig {
+    // Default: /var/lib/zuul/outbox.json
+    path: Option<PathBuf>,
+    /// Events kept before the oldest are dropped. Default: 1000
+    capacity: Option<usize>,
+}
+
+/// How callers' numbers show up on MQTT and in the logs; see `privacy`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PrivacyConfig {
+    /// For numbers that appear in the whitelist. Default: clear
+    members: Option<NumberDisplay>,
+    /// For everyone else. Default: clear
+    unknown: Option<NumberDisplay>,
+    /// Digits left at the end of masked numbers. Default: 3
+    visible_digits: Option<usize>,
+    /// Key for pseudonyms, without which they could be undone by trying every number
+    salt: Option<String>,
+    salt_file: Option<PathBuf>,
+}
+
+/// Keeps unknown callers on file until an admin approves or dismisses them
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PendingConfig {
+    // Default: /var/lib/zuul/pending.json
+    path: Option<PathBuf>,
+}
+
+/// Tone sequences for `AT+VTS`, e.g. `1,5,9`, played to callers once their call is decided
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct FeedbackConfig {
+    // Default: 1,5,9
+    accepted: Option<String>,
+    // Default: 9,9,9
+    denied: Option<String>,
+    // Default: 5,5
+    out_of_hours: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RingIndicatorConfig {
+    /// BCM number of the GPIO the modem RI line is connected to
+    pub pin: u8,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ClockConfig {
+    /// How time-based rules are evaluated before the time is known. Default: fail-closed
+    #[serde(default)]
+    pub unknown_time: UnknownTimePolicy,
+}
+
+/// Whether callers can match the whitelist when the network can't vouch for their number
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CallerIdConfig {
+    /// Let callers without a number match rules that don't name one. Default: false
+    #[serde(default)]
+    pub allow_withheld: bool,
+    /// Let numbers the network did not verify match the whitelist. Default: false
+    #[serde(default)]
+    pub allow_unscreened: bool,
+    /// Let numbers match the whitelist when the modem doesn't say whether the network verified
+    /// them, for modems that leave the validity out of `+CLIP`. Default: false
+    #[serde(default)]
+    pub allow_missing_validity: bool,
+}
+
+#[derive(Default, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub sockets: SocketPaths,
     pub mqtt: Option<MqttConfig>,
+    /// How to reach the modem. Default: the `--modem` serial port
+    pub modem: Option<TransportConfig>,
+    #[serde(default)]
+    pub sim: SimConfig,
+    pub balance: Option<BalanceConfig>,
+    #[serde(default)]
+    pub clock: ClockConfig,
+    pub ring_indicator: Option<RingIndicatorConfig>,
+    #[serde(default)]
+    pub caller_id: CallerIdConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    pub keypad: Option<KeypadConfig>,
+    pub pending: Option<PendingConfig>,
+    pub commands: Option<CommandsConfig>,
+    pub whitelist_push: Option<WhitelistPushConfig>,
+    pub outbox: Option<OutboxConfig>,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    pub feedback: Option<FeedbackConfig>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let source = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&source)?;
+        if let Some(ref feedback) = config.feedback {
+            feedback.validate()?;
+        }
+        if let Some(ref balance) = config.balance {
+            balance.validate()?;
+        }
+        config.sim.validate()?;
+        if let Some(ref modem) = config.modem {
+            modem.validate()?;
+        }
+        if let Some(ref mqtt) = config.mqtt {
+            mqtt.validate()?;
+            validate_events(&mqtt.events)?;
+        }
+        if let Some(ref push) = config.whitelist_push {
+            push.validate()?;
+        }
+        if let Some(ref home_assistant) = config.home_assistant {
+            home_assistant.validate()?;
+        }
+        for webhook in &config.notify.webhook {
+            validate_events(&webhook.events)?;
+        }
+        for exec in &config.notify.exec {
+            validate_events(&exec.events)?;
+        }
+        Ok(config)
+    }
+}
+
+/// A misspelt event type would quietly never match
+fn validate_events(events: &[String]) -> Result<(), Error> {
+    for event in events {
+        if !EVENTS.contains(&event.as_str()) {
+            bail!(
+                "Unknown event type {:?}; known are {}",
+                event,
+                EVENTS.join(", ")
+            );
+        }
+    }
+    Ok(())
 }
 
 impl SocketPaths {
-    pub fn modem_urc(&self) -> &str {
-        self.modem_urc
-            .as_ref()
-            .map(String::as_str)
-            .unwrap_or("inproc://modem_urc")
+    pub fn control(&self) -> &Path {
+        self.control
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/run/zuul/control.sock"))
+    }
+}
+
+impl CallerIdConfig {
+    /// Whether a caller whose number has this validity can match the whitelist
+    pub fn trusts(&self, validity: CliValidity) -> bool {
+        match validity {
+            CliValidity::Valid => true,
+            CliValidity::Withheld | CliValidity::Unavailable => self.allow_withheld,
+            CliValidity::Unscreened => self.allow_unscreened,
+            CliValidity::Unknown(_) => false,
+            CliValidity::Missing => self.allow_missing_validity,
+        }
+    }
+}
+
+impl FeedbackConfig {
+    pub fn tones(&self, decision: &Decision) -> &str {
+        match decision {
+            Decision::Accept(_) => self.accepted.as_deref().unwrap_or("1,5,9"),
+            // Telling callers a knock is missing would help whoever spoofs their number
+            Decision::Deny | Decision::AwaitingKnock => self.denied.as_deref().unwrap_or("9,9,9"),
+            Decision::OutOfHours => self.out_of_hours.as_deref().unwrap_or("5,5"),
+        }
+    }
+
+    /// The sequences are sent to the modem as they are, so only allow what `AT+VTS` accepts
+    fn validate(&self) -> Result<(), Error> {
+        let sequences = [&self.accepted, &self.denied, &self.out_of_hours];
+        for tones in sequences.iter().filter_map(|tones| tones.as_ref()) {
+            if tones.is_empty() || !tones.chars().all(|c| "0123456789ABCD*#,{}".contains(c)) {
+                bail!("Invalid tone sequence {:?}", tones);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KeypadConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(20))
     }
 
-    pub fn modem_rpc(&self) -> &str {
-        self.modem_rpc
-            .as_ref()
-            .map(String::as_str)
-            .unwrap_or("inproc://modem_rpc")
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(3)
     }
 
-    pub fn event(&self) -> &str {
-        self.event
-            .as_ref()
-            .map(String::as_str)
-            .unwrap_or("inproc://event")
+    pub fn max_attempts_total(&self) -> u32 {
+        self.max_attempts_total.unwrap_or(10)
+    }
+
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout.unwrap_or(900))
+    }
+}
+
+impl MqttConfig {
+    pub fn uri(&self) -> String {
+        let scheme = if self.tls.is_some() { "ssl" } else { "tcp" };
+        format!("{}://{}:{}", scheme, self.server, self.port)
+    }
+
+    pub fn password(&self) -> Result<Option<String>, Error> {
+        Ok(match (&self.password, &self.password_file) {
+            (Some(password), None) => Some(password.clone()),
+            (None, Some(path)) => Some(std::fs::read_to_string(path)?.trim_end().to_owned()),
+            (None, None) => None,
+            (Some(_), Some(_)) => bail!("MQTT needs at most one of password and password_file"),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.username.is_none() && (self.password.is_some() || self.password_file.is_some()) {
+            bail!("An MQTT password needs a username");
+        }
+        if let Some(ref tls) = self.tls {
+            tls.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl TlsConfig {
+    pub fn verify(&self) -> bool {
+        self.verify.unwrap_or(true)
+    }
+
+    pub fn verify_hostname(&self) -> bool {
+        self.verify_hostname.unwrap_or_else(|| self.verify())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.cert.is_some() != self.key.is_some() {
+            bail!("A TLS client certificate needs both cert and key");
+        }
+        if self.verify_hostname() && !self.verify() {
+            bail!("TLS verify_hostname needs verify");
+        }
+        Ok(())
+    }
+}
+
+impl CommandsConfig {
+    pub fn secret(&self) -> Result<Vec<u8>, Error> {
+        let secret = match (&self.secret, &self.secret_file) {
+            (Some(secret), None) => secret.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?.trim().to_owned(),
+            _ => bail!("Commands need exactly one of secret and secret_file"),
+        };
+        if secret.is_empty() {
+            bail!("The command secret is empty");
+        }
+        Ok(secret.into_bytes())
+    }
+
+    pub fn max_age(&self) -> i64 {
+        self.max_age.unwrap_or(60)
+    }
+
+    pub fn lockdown_state(&self) -> &Path {
+        self.lockdown_state
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/lib/zuul/lockdown"))
+    }
+}
+
+impl WhitelistPushConfig {
+    pub fn public_key(&self) -> Result<Vec<u8>, Error> {
+        match hex::decode(self.public_key.trim()) {
+            Ok(key) => Ok(key),
+            Err(_) => bail!("The whitelist push key must be in hex"),
+        }
+    }
+
+    pub fn state(&self) -> &Path {
+        self.state
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/lib/zuul/whitelist-push.json"))
+    }
+
+    /// The unit ends up in a topic, which must not match anyone else's
+    fn validate(&self) -> Result<(), Error> {
+        if self.unit.is_empty() || self.unit.contains(&['/', '+', '#'][..]) {
+            bail!("Invalid unit name for whitelist push: {:?}", self.unit);
+        }
+        Ok(())
+    }
+}
+
+impl WebhookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+impl HomeAssistantConfig {
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("homeassistant")
+    }
+
+    /// The token of the open button, if there is one
+    pub fn open_token(&self) -> Option<&str> {
+        self.open_token.as_deref().filter(|_| self.remote_open)
+    }
+
+    /// The token is the only thing between the broker and the door
+    fn validate(&self) -> Result<(), Error> {
+        if !self.remote_open {
+            return Ok(());
+        }
+        match self.open_token {
+            Some(ref token)
+                if token.len() >= 16
+                    && token
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {}
+            Some(_) => bail!("The open token needs at least 16 letters, digits, - or _"),
+            None => bail!("remote_open needs an open_token"),
+        }
+        Ok(())
+    }
+}
+
+impl OutboxConfig {
+    pub fn path(&self) -> &Path {
+        self.path
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/lib/zuul/outbox.json"))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.unwrap_or(1000)
+    }
+}
+
+impl PrivacyConfig {
+    pub fn members(&self) -> NumberDisplay {
+        self.members.unwrap_or(NumberDisplay::Clear)
+    }
+
+    pub fn unknown(&self) -> NumberDisplay {
+        self.unknown.unwrap_or(NumberDisplay::Clear)
+    }
+
+    pub fn visible_digits(&self) -> usize {
+        self.visible_digits.unwrap_or(3)
+    }
+
+    pub fn salt(&self) -> Result<Vec<u8>, Error> {
+        let salt = match (&self.salt, &self.salt_file) {
+            (Some(salt), None) => salt.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?.trim().to_owned(),
+            _ => bail!("Pseudonyms need exactly one of salt and salt_file"),
+        };
+        if salt.is_empty() {
+            bail!("The pseudonym salt is empty");
+        }
+        Ok(salt.into_bytes())
+    }
+}
+
+impl PendingConfig {
+    pub fn path(&self) -> &Path {
+        self.path
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/lib/zuul/pending.json"))
+    }
+}
+
+impl RateLimitConfig {
+    pub fn caller_burst(&self) -> u32 {
+        self.caller_burst.unwrap_or(5)
+    }
+
+    pub fn caller_refill(&self) -> Duration {
+        Duration::from_secs(self.caller_refill.unwrap_or(60))
+    }
+
+    pub fn global_burst(&self) -> Option<u32> {
+        self.global_burst
+    }
+
+    pub fn global_refill(&self) -> Duration {
+        Duration::from_secs(self.global_refill.unwrap_or(6))
+    }
+
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout.unwrap_or(900))
+    }
+
+    pub fn alert(&self) -> bool {
+        self.alert.unwrap_or(true)
+    }
+}
+
+impl SimConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(ref pin) = self.pin {
+            if pin.len() < 4 || pin.len() > 8 || !pin.chars().all(|c| c.is_ascii_digit()) {
+                bail!("Invalid SIM PIN; it has 4 to 8 digits");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BalanceConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(86400))
+    }
+
+    /// The code goes into an AT command line, where a `"` or `;` would start another command
+    fn validate(&self) -> Result<(), Error> {
+        if self.code.is_empty() || !self.code.chars().all(|c| "0123456789*#".contains(c)) {
+            bail!("Invalid USSD code {:?}", self.code);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tls(config: &str) -> Result<TlsConfig, Error> {
+        let tls: TlsConfig = toml::from_str(config)?;
+        tls.validate()?;
+        Ok(tls)
+    }
+
+    #[test]
+    fn test_missing_validity() {
+        let default: CallerIdConfig = toml::from_str("").unwrap();
+        assert!(default.trusts(CliValidity::Valid));
+        assert!(!default.trusts(CliValidity::Missing));
+        let allowed: CallerIdConfig = toml::from_str("allow_missing_validity = true").unwrap();
+        assert!(allowed.trusts(CliValidity::Missing));
+        assert!(!allowed.trusts(CliValidity::Unscreened));
+    }
+
+    #[test]
+    fn test_tls_verify() {
+        let default = tls("").unwrap();
+        assert!(default.verify() && default.verify_hostname());
+
+        let chain_only = tls("verify_hostname = false").unwrap();
+        assert!(chain_only.verify() && !chain_only.verify_hostname());
+
+        let none = tls("verify = false").unwrap();
+        assert!(!none.verify() && !none.verify_hostname());
+
+        assert!(tls("verify = false\nverify_hostname = true").is_err());
+        assert!(tls("verify = \"yes\"").is_err());
     }
 }
@@ -0,0 +1,279 @@
+//! The control socket, for poking at a running daemon without interrupting door service.
+//!
+//! Requests are single lines, e.g. `at AT+CSQ`. Each is answered by any number of lines of text,
+//! followed by a final line that is either `OK` or starts with `ERROR`, `+CME ERROR` or
+//! `+CMS ERROR`.
+//!
+//! * `at <command>`: pass an AT command on to the modem
+//! * `pending`: list the unknown callers waiting for approval
+//! * `approve <caller> <label> [<until>]`: add a whitelist rule for a pending caller, given by
+//!   number or `#<id>`, e.g. `approve 32470000002 Bob 2019-12-31`. Not while the whitelist is pushed, as the next push
+//!   would drop the rule again.
+//! * `dismiss <caller>`: drop a pending caller
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use chrono::NaiveDate;
+use slog::{debug, info, warn, Logger};
+
+use crate::event::Event;
+use crate::modem::{Request, Response};
+use crate::pending::PendingList;
+use crate::privacy::Privacy;
+
+/// What the control socket needs to act on pending callers
+#[derive(Clone)]
+pub struct Approvals {
+    pub pending: Arc<Mutex<PendingList>>,
+    /// None if the whitelist is pushed
+    pub whitelist: Option<PathBuf>,
+    pub events: mpsc::Sender<Event>,
+    /// For the numbers in the log; `zuul pending` is for admins, and shows them as they are
+    pub privacy: Privacy,
+}
+
+/// Why running `command` (a single command, without the `AT` prefix) would break the session
+/// between the driver and the modem, if it would
+fn forbidden(command: &str) -> Option<&'static str> {
+    // Read and test commands never change anything
+    if command.ends_with('?') {
+        return None;
+    }
+    match command {
+        "Q1" => Some("suppresses result codes"),
+        "V" | "V0" => Some("switches to numeric result codes"),
+        "+CREG=0" => Some("disables registration reports"),
+        "+CLIP=0" => Some("disables caller identification"),
+        _ if command.starts_with('Z') => Some("resets the modem"),
+        _ if command.starts_with("&F") => Some("restores the factory settings"),
+        _ if command.starts_with("+CPOWD") => Some("powers the modem down"),
+        _ if command.starts_with("+IPR") => Some("changes the baud rate"),
+        _ if command.starts_with("+CFUN") && command != "+CFUN=1" => {
+            Some("changes the phone functionality")
+        }
+        _ => None,
+    }
+}
+
+/// Check that a command line sent through the control socket is safe to pass on to the modem
+fn check_command(line: &str) -> Result<(), String> {
+    let line = line.to_ascii_uppercase();
+    if !line.is_ascii() || line.chars().any(|c| c.is_control()) {
+        return Err("Only printable ASCII is allowed".to_owned());
+    }
+    if !line.starts_with("AT") {
+        return Err("Not an AT command".to_owned());
+    }
+
+    // Split the line into its commands, e.g. `ATE1+CREG=1;+CLIP=1` into `E1`, `+CREG=1`, `+CLIP=1`
+    let mut rest = &line[2..];
+    while !rest.is_empty() {
+        let len = match rest.as_bytes()[0] {
+            // Extended commands run up to the next `;`
+            b'+' | b'*' | b'^' | b'$' => rest.find(';').unwrap_or(rest.len()),
+            // Basic commands are a letter, possibly after a `&`, and a number
+            first => {
+                let name = if first == b'&' { rest.len().min(2) } else { 1 };
+                name + rest[name..].bytes().take_while(u8::is_ascii_digit).count()
+            }
+        };
+        let command = &rest[..len];
+        if let Some(reason) = forbidden(command) {
+            return Err(format!("AT{} {}", command, reason));
+        }
+        rest = rest[len..].trim_start_matches(';');
+    }
+    Ok(())
+}
+
+fn serve(
+    stream: UnixStream,
+    modem: &mpsc::Sender<Request>,
+    approvals: Option<&Approvals>,
+    logger: &Logger,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let mut words = line.trim().splitn(2, ' ');
+        let reply = match (words.next(), words.next(), approvals) {
+            (Some(""), _, _) => continue,
+            (Some("at"), Some(cmd), _) => passthrough(cmd.trim(), modem, logger),
+            (Some("pending"), None, Some(approvals)) => list_pending(approvals),
+            (Some("approve"), Some(args), Some(approvals)) => approve(args, approvals, logger),
+            (Some("dismiss"), Some(caller), Some(approvals)) => {
+                dismiss(caller.trim(), approvals, logger)
+            }
+            (Some("pending"), _, None)
+            | (Some("approve"), _, None)
+            | (Some("dismiss"), _, None) => {
+                vec!["ERROR: No pending list is kept".to_owned()]
+            }
+            _ => vec!["ERROR: Unknown request".to_owned()],
+        };
+        for line in reply {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+fn passthrough(cmd: &str, modem: &mpsc::Sender<Request>, logger: &Logger) -> Vec<String> {
+    if let Err(reason) = check_command(cmd) {
+        warn!(logger, "Rejected passthrough command"; "cmd" => cmd, "reason" => &reason);
+        return vec![format!("ERROR: {}", reason)];
+    }
+
+    info!(logger, "Passthrough command"; "cmd" => cmd);
+    let (reply, response) = mpsc::channel();
+    let sent = modem.send(Request {
+        cmd: cmd.to_owned(),
+        reply,
+    });
+    match sent.ok().and_then(|_| response.recv().ok()) {
+        Some(Response { mut text, result }) => {
+            text.push(result.unwrap_or_else(|| "ERROR: Timed out".to_owned()));
+            text
+        }
+        None => vec!["ERROR: Modem is not running".to_owned()],
+    }
+}
+
+fn list_pending(approvals: &Approvals) -> Vec<String> {
+    let pending = approvals.pending.lock().unwrap();
+    let mut reply: Vec<String> = pending
+        .callers()
+        .iter()
+        .map(|caller| {
+            let time = |time: &Option<String>| time.clone().unwrap_or_else(|| "-".to_owned());
+            format!(
+                "{} id={} calls={} first={} last={}",
+                caller.number,
+                caller.id,
+                caller.calls,
+                time(&caller.first_call),
+                time(&caller.last_call)
+            )
+        })
+        .collect();
+    reply.push("OK".to_owned());
+    reply
+}
+
+fn approve(args: &str, approvals: &Approvals, logger: &Logger) -> Vec<String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (caller, label, until) = match args[..] {
+        [caller, label] => (caller, label, None),
+        [caller, label, until] => match NaiveDate::parse_from_str(until, "%Y-%m-%d") {
+            Ok(until) => (caller, label, Some(until)),
+            Err(_) => return vec!["ERROR: Expiry must be a date, e.g. 2019-12-31".to_owned()],
+        },
+        _ => return vec!["ERROR: Usage: approve <caller> <label> [<until>]".to_owned()],
+    };
+
+    let whitelist = match approvals.whitelist {
+        Some(ref whitelist) => whitelist,
+        None => {
+            return vec![
+                "ERROR: Whitelist is pushed; approve callers where it is signed".to_owned(),
+            ]
+        }
+    };
+    let mut pending = approvals.pending.lock().unwrap();
+    match pending.approve(caller, label, until, whitelist) {
+        Ok(caller) => {
+            info!(logger, "Approved pending caller";
+                  "number" => approvals.privacy.show(&caller.number, true), "label" => label);
+            approvals
+                .events
+                .send(Event::WhitelistChanged)
+                .expect("Event processing thread is dead");
+            vec!["OK".to_owned()]
+        }
+        Err(err) => vec![format!("ERROR: {}", err)],
+    }
+}
+
+fn dismiss(caller: &str, approvals: &Approvals, logger: &Logger) -> Vec<String> {
+    let mut pending = approvals.pending.lock().unwrap();
+    match pending.dismiss(caller) {
+        Ok(caller) => {
+            info!(logger, "Dismissed pending caller";
+                  "number" => approvals.privacy.show(&caller.number, false));
+            approvals
+                .events
+                .send(Event::PendingChanged)
+                .expect("Event processing thread is dead");
+            vec!["OK".to_owned()]
+        }
+        Err(err) => vec![format!("ERROR: {}", err)],
+    }
+}
+
+/// Listen for control connections at `path`, passing AT commands on to the modem, and acting on
+/// pending callers if there is a list of them
+pub fn spawn<P: AsRef<Path>>(
+    path: P,
+    modem: mpsc::Sender<Request>,
+    approvals: Option<Approvals>,
+    logger: Logger,
+) -> io::Result<thread::JoinHandle<()>> {
+    // A socket left behind by an earlier run would make the bind fail
+    match fs::remove_file(path.as_ref()) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        other => other?,
+    }
+    let listener = UnixListener::bind(path)?;
+
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let modem = modem.clone();
+                        let approvals = approvals.clone();
+                        let logger = logger.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = serve(stream, &modem, approvals.as_ref(), &logger) {
+                                debug!(logger, "Control connection failed"; "error" => %err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        warn!(logger, "Failed to accept control connection"; "error" => %err)
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_command() {
+        assert!(check_command("AT+CSQ").is_ok());
+        assert!(check_command("at+cops?").is_ok());
+        assert!(check_command("ATE1+CREG=1;+CLIP=1").is_ok());
+        assert!(check_command("AT+CFUN?").is_ok());
+        assert!(check_command("AT+CFUN=1").is_ok());
+        assert!(check_command("AT+CUSD=1,\"*101#\",15").is_ok());
+
+        assert!(check_command("AT+CFUN=0").is_err());
+        assert!(check_command("at+cfun=1,1").is_err());
+        assert!(check_command("ATZ").is_err());
+        assert!(check_command("ATE1Z0").is_err());
+        assert!(check_command("AT+CSQ;&F").is_err());
+        assert!(check_command("AT+IPR=9600").is_err());
+        assert!(check_command("ATQ1").is_err());
+        assert!(check_command("AT+CSQ\rATZ").is_err());
+        assert!(check_command("+CSQ").is_err());
+    }
+}
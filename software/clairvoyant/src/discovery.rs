@@ -0,0 +1,151 @@
+//! Home Assistant MQTT discovery, so that a door unit shows up as a device without any YAML.
+//! The entities read the topics we publish anyway; the device is identified by the modem IMEI.
+
+use serde_json::{json, Value};
+
+use crate::event::Inventory;
+
+/// What the button entity publishes
+pub const OPEN_PAYLOAD: &str = "PRESS";
+
+/// Pressing the button entity publishes here. HA can't sign commands, so this is separate from
+/// `zuul/cmd/+`, and only subscribed to when enabled. The secret `token` keeps others out.
+pub fn open_topic(token: &str) -> String {
+    format!("zuul/ha/open/{}", token)
+}
+
+/// The retained discovery configs to publish, by topic, or None until the IMEI is known. There
+/// is an open button if there is an `open_token`.
+pub fn configs(
+    prefix: &str,
+    inventory: &Inventory,
+    open_token: Option<&str>,
+) -> Option<Vec<(String, Value)>> {
+    let imei = inventory.imei.as_ref()?;
+    let node = format!("zuul_{}", imei);
+    let device = json!({
+        "identifiers": [node],
+        "name": "Zuul",
+        "manufacturer": inventory.manufacturer,
+        "model": inventory.model,
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let availability = json!({
+        "topic": "zuul/online",
+        "payload_available": "true",
+        "payload_not_available": "false",
+    });
+
+    let mut entities = vec![
+        (
+            "binary_sensor",
+            "online",
+            json!({
+                "name": "Online",
+                "state_topic": "zuul/online",
+                "payload_on": "true",
+                "payload_off": "false",
+                "device_class": "connectivity",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "registered",
+            json!({
+                "name": "GSM registered",
+                "state_topic": "zuul/status",
+                "value_template":
+                    "{{ 'ON' if value_json.registration in ['registered', 'roaming'] else 'OFF' }}",
+                "device_class": "connectivity",
+                "availability": [availability],
+            }),
+        ),
+        (
+            "sensor",
+            "signal",
+            json!({
+                "name": "Signal strength",
+                "state_topic": "zuul/status",
+                "value_template": "{{ value_json.signal_dbm }}",
+                "unit_of_measurement": "dBm",
+                "device_class": "signal_strength",
+                "availability": [availability],
+            }),
+        ),
+        (
+            "sensor",
+            "last_caller",
+            json!({
+                "name": "Last caller",
+                "state_topic": "zuul/status",
+                "value_template": "{{ (value_json.last_decision or {}).label or 'unknown' }}",
+                "availability": [availability],
+            }),
+        ),
+        (
+            "event",
+            "ring",
+            json!({
+                "name": "Ring",
+                "state_topic": "zuul/ring",
+                "event_types": ["ring"],
+                "value_template": "{{ {'event_type': value_json.event} | to_json }}",
+                "availability": [availability],
+            }),
+        ),
+    ];
+    if let Some(token) = open_token {
+        entities.push((
+            "button",
+            "open",
+            json!({
+                "name": "Open door",
+                "command_topic": open_topic(token),
+                "payload_press": OPEN_PAYLOAD,
+                "availability": [availability],
+            }),
+        ));
+    }
+
+    let configs = entities
+        .into_iter()
+        .map(|(component, object, mut config)| {
+            config["unique_id"] = json!(format!("{}_{}", node, object));
+            config["device"] = device.clone();
+            let topic = format!("{}/{}/{}/{}/config", prefix, component, node, object);
+            (topic, config)
+        })
+        .collect();
+    Some(configs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_configs() {
+        let mut inventory = Inventory::default();
+        assert!(configs("homeassistant", &inventory, Some("0123456789abcdef")).is_none());
+
+        inventory.imei = Some("866000000000001".to_owned());
+        let published = configs("homeassistant", &inventory, None).unwrap();
+        let (topic, config) = &published[0];
+        assert_eq!(
+            topic,
+            "homeassistant/binary_sensor/zuul_866000000000001/online/config"
+        );
+        assert_eq!(config["unique_id"], "zuul_866000000000001_online");
+        assert_eq!(config["device"]["identifiers"][0], "zuul_866000000000001");
+        assert!(published
+            .iter()
+            .all(|(topic, _)| !topic.contains("/button/")));
+
+        let published = configs("homeassistant", &inventory, Some("0123456789abcdef")).unwrap();
+        let (_, button) = published
+            .iter()
+            .find(|(topic, _)| topic.contains("/button/"))
+            .unwrap();
+        assert_eq!(button["command_topic"], "zuul/ha/open/0123456789abcdef");
+    }
+}
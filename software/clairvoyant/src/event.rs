@@ -1,4 +1,8 @@
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Regstate {
     Unregistered,
     Registered,
@@ -8,10 +12,122 @@ pub enum Regstate {
     Unknown(i32),
 }
 
+/// How far the modem has come in setting up a session
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModemState {
+    /// Powered down, so that it can come up in a known state
+    PoweredOff,
+    /// Powered up, waiting for `RDY`
+    Booting,
+    /// Asking whether the SIM wants a PIN
+    SimCheck,
+    Unlocking,
+    /// Enabling the URCs we rely on
+    Configuring,
+    /// Waiting for the network
+    Registering,
+    Ready,
+    /// Setup failed; the modem is power cycled shortly
+    Fault,
+    /// The SIM rejected its PIN or wants its PUK. Retrying would only use up attempts, so this
+    /// lasts until someone has a look.
+    SimLocked,
+}
+
+/// Signs that the modem or its wiring is in trouble, while it may still seem to work
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// RI was asserted, but nothing arrived on the serial port; the UART may be wedged
+    RingWithoutUrc,
+    /// A call was reported on the serial port without RI being asserted
+    UrcWithoutRing,
+}
+
+/// Whether the network vouches for a caller's number, from the `<CLI validity>` of `+CLIP`
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq)]
+pub enum CliValidity {
+    Valid,
+    /// The caller withheld their number
+    Withheld,
+    /// The originating network did not pass a number on
+    Unavailable,
+    /// The number was provided by the caller, and not verified by the network
+    Unscreened,
+    /// A code we don't know the meaning of, so it vouches for nothing
+    Unknown(u8),
+    /// The modem left the validity out, as older ones do
+    Missing,
+}
+
+impl CliValidity {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => CliValidity::Valid,
+            1 => CliValidity::Withheld,
+            2 => CliValidity::Unavailable,
+            3 => CliValidity::Unscreened,
+            code => CliValidity::Unknown(code),
+        }
+    }
+
+    /// Why a number with this validity can't be taken at face value, if it can't
+    pub fn reason(self) -> Option<&'static str> {
+        match self {
+            CliValidity::Valid => None,
+            CliValidity::Withheld => Some("withheld by the caller"),
+            CliValidity::Unavailable => Some("not available from the network"),
+            CliValidity::Unscreened => Some("not screened by the network"),
+            CliValidity::Unknown(_) => Some("of unknown validity"),
+            CliValidity::Missing => Some("without a validity from the modem"),
+        }
+    }
+}
+
+/// The caller of an incoming call, as reported by `+CLIP`
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
+pub struct CallerId {
+    /// Empty if the number was withheld or is not available
+    pub number: String,
+    /// Type of address, e.g. 145 for international numbers
+    pub number_type: u8,
+    pub validity: CliValidity,
+    /// The caller's name from the phonebook on the SIM
+    pub alpha: Option<String>,
+}
+
+/// Identifies the modem and SIM of a door unit
+#[derive(Clone, Debug, Default, PartialOrd, Ord, PartialEq, Eq, Serialize)]
+pub struct Inventory {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    pub imei: Option<String>,
+    pub imsi: Option<String>,
+    pub iccid: Option<String>,
+}
+
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Event {
     Heartbeat,
-    Ring(String),
+    Ring(CallerId),
     Creg(Regstate),
+    /// Signal strength as reported by `AT+CSQ`, from 0 to 31, or None if unknown
+    Signal(Option<u8>),
     GsmOk,
+    Ussd(String),
+    NetworkTime(DateTime<FixedOffset>),
+    Inventory(Inventory),
+    ModemState(ModemState),
+    Diagnostic(Diagnostic),
+    /// A key pressed by the caller, once DTMF detection is enabled with `AT+DDET=1`
+    Dtmf(char),
+    /// The call ended
+    Hangup,
+    /// A rule was added to the whitelist file
+    WhitelistChanged,
+    /// A pending caller was dismissed
+    PendingChanged,
+    /// The modem can't be reached any more, e.g. because ser2net closed the connection
+    ModemLost(String),
 }
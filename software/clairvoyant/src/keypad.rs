@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::KeypadConfig;
+
+/// Longest PIN accepted; anything longer is somebody leaning on the keys
+const MAX_PIN_LEN: usize = 16;
+
+/// Numbers whose failures are remembered. Beyond this, those heard from least recently are
+/// forgotten; the total still counts them.
+const MAX_TRACKED: usize = 1000;
+
+/// How often the keypad lockout can double, i.e. it lasts at most 64 times `lockout`
+const MAX_BACKOFF: u32 = 6;
+
+/// Where the failures of callers whose number the network doesn't vouch for are counted, all
+/// together, as they could get around a lockout by showing another number
+const UNVERIFIED: &str = "unverified";
+
+/// A call answered so that the caller can enter a PIN
+struct Session {
+    number: String,
+    verified: bool,
+    digits: String,
+    started: Instant,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// A PIN entered by a caller, terminated with `#`
+pub struct Entry {
+    pub number: String,
+    /// Whether the network vouches for `number`
+    pub verified: bool,
+    pub pin: String,
+}
+
+/// What the failures of a caller are counted under
+fn lockout_key(number: &str, verified: bool) -> &str {
+    if verified {
+        number
+    } else {
+        UNVERIFIED
+    }
+}
+
+/// Collects PINs entered by callers as DTMF, and locks out callers who keep getting them wrong.
+/// Callers can change their number, so too many wrong PINs from all of them together shut the
+/// keypad for everyone.
+pub struct Keypad {
+    timeout: Duration,
+    max_attempts: u32,
+    max_attempts_total: u32,
+    lockout: Duration,
+    session: Option<Session>,
+    failures: HashMap<String, Failures>,
+    total: Failures,
+}
+
+impl Keypad {
+    pub fn new(config: &KeypadConfig) -> Self {
+        Keypad {
+            timeout: config.timeout(),
+            max_attempts: config.max_attempts(),
+            max_attempts_total: config.max_attempts_total(),
+            lockout: config.lockout(),
+            session: None,
+            failures: HashMap::new(),
+            total: Failures {
+                count: 0,
+                last: Instant::now(),
+            },
+        }
+    }
+
+    /// How long after the last of `count` wrong PINs from everyone the keypad stays shut, or
+    /// they are still counted if it isn't
+    fn total_lockout(&self, count: u32) -> Duration {
+        let doublings = count
+            .saturating_sub(self.max_attempts_total)
+            .min(MAX_BACKOFF);
+        self.lockout * 2u32.pow(doublings)
+    }
+
+    /// Whether too many wrong PINs have been entered, from whatever numbers
+    pub fn is_shut(&self) -> bool {
+        self.total.count >= self.max_attempts_total
+            && self.total.last.elapsed() < self.total_lockout(self.total.count)
+    }
+
+    pub fn in_call(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Whether `number` is the caller entering a PIN
+    pub fn in_call_with(&self, number: &str) -> bool {
+        matches!(self.session, Some(ref session) if session.number == number)
+    }
+
+    /// Whether `number` may not enter a PIN, also if the keypad is shut for everyone
+    pub fn is_locked_out(&self, number: &str, verified: bool) -> bool {
+        if self.is_shut() {
+            return true;
+        }
+        match self.failures.get(lockout_key(number, verified)) {
+            Some(failures) => {
+                failures.count >= self.max_attempts && failures.last.elapsed() < self.lockout
+            }
+            None => false,
+        }
+    }
+
+    pub fn start(&mut self, number: String, verified: bool) {
+        self.session = Some(Session {
+            number,
+            verified,
+            digits: String::new(),
+            started: Instant::now(),
+        });
+    }
+
+    /// Handle a key pressed by the caller. `*` starts over, and `#` completes the entry.
+    pub fn key(&mut self, key: char) -> Option<Entry> {
+        let session = self.session.as_mut()?;
+        match key {
+            '*' => session.digits.clear(),
+            '#' => {
+                let session = self.session.take()?;
+                return Some(Entry {
+                    number: session.number,
+                    verified: session.verified,
+                    pin: session.digits,
+                });
+            }
+            digit if digit.is_ascii_digit() && session.digits.len() < MAX_PIN_LEN => {
+                session.digits.push(digit)
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Ends the session if the caller took too long, returning whether it did
+    pub fn expire(&mut self) -> bool {
+        match self.session {
+            Some(ref session) if session.started.elapsed() > self.timeout => {
+                self.session = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn end(&mut self) {
+        self.session = None;
+    }
+
+    /// Record a wrong PIN, returning whether the caller is now locked out
+    pub fn fail(&mut self, number: &str, verified: bool) -> bool {
+        if self.total.last.elapsed() >= self.total_lockout(self.total.count) {
+            self.total.count = 0;
+        }
+        self.total.count += 1;
+        self.total.last = Instant::now();
+
+        let key = lockout_key(number, verified);
+        let lockout = self.lockout;
+        // Failures whose lockout has run out count for nothing
+        self.failures
+            .retain(|_, failures| failures.last.elapsed() < lockout);
+        if self.failures.len() >= MAX_TRACKED && !self.failures.contains_key(key) {
+            let oldest = self
+                .failures
+                .iter()
+                .min_by_key(|(_, failures)| failures.last)
+                .map(|(number, _)| number.clone());
+            if let Some(oldest) = oldest {
+                self.failures.remove(&oldest);
+            }
+        }
+        let failures = self.failures.entry(key.to_owned()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        // A lockout that has run out starts a fresh count
+        if failures.last.elapsed() >= lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = Instant::now();
+        self.is_locked_out(number, verified)
+    }
+
+    pub fn succeed(&mut self, number: &str, verified: bool) {
+        self.failures.remove(lockout_key(number, verified));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keypad() -> Keypad {
+        Keypad::new(&toml::from_str("max_attempts = 2").unwrap())
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut keypad = keypad();
+        assert!(keypad.key('1').is_none());
+
+        keypad.start("32470000001".to_owned(), true);
+        assert!(keypad.in_call_with("32470000001"));
+        assert!(!keypad.in_call_with("32470000002"));
+        for key in "12*4711".chars() {
+            assert!(keypad.key(key).is_none());
+        }
+        let entry = keypad.key('#').unwrap();
+        assert_eq!(entry.number, "32470000001");
+        assert!(entry.verified);
+        assert_eq!(entry.pin, "4711");
+        assert!(!keypad.in_call());
+    }
+
+    #[test]
+    fn test_lockout() {
+        let mut keypad = keypad();
+        assert!(!keypad.fail("32470000001", true));
+        assert!(keypad.fail("32470000001", true));
+        assert!(keypad.is_locked_out("32470000001", true));
+        assert!(!keypad.is_locked_out("32470000002", true));
+
+        keypad.succeed("32470000001", true);
+        assert!(!keypad.is_locked_out("32470000001", true));
+    }
+
+    #[test]
+    fn test_unverified_lockout() {
+        let mut keypad = keypad();
+        assert!(!keypad.fail("32470000001", false));
+        assert!(keypad.fail("", false));
+        assert!(keypad.is_locked_out("32470000002", false));
+        assert!(!keypad.is_locked_out("32470000001", true));
+    }
+
+    #[test]
+    fn test_total_lockout() {
+        let mut keypad = Keypad::new(&toml::from_str("max_attempts_total = 3").unwrap());
+        assert!(!keypad.fail("32470000001", true));
+        assert!(!keypad.fail("32470000002", true));
+        assert!(keypad.fail("32470000003", true));
+        assert!(keypad.is_shut());
+        assert!(keypad.is_locked_out("32470000004", true));
+        assert_eq!(keypad.total_lockout(3), keypad.lockout);
+        assert_eq!(keypad.total_lockout(5), keypad.lockout * 4);
+        assert_eq!(keypad.total_lockout(100), keypad.lockout * 64);
+
+        for n in 0..MAX_TRACKED as u64 + 10 {
+            keypad.fail(&(32470000000 + n).to_string(), true);
+        }
+        assert!(keypad.failures.len() <= MAX_TRACKED);
+    }
+}
@@ -0,0 +1,75 @@
+//! Remembers recent calls per number, for rules that make callers knock: call, hang up and call
+//! again. A spoofed caller ID gets a single call through, but rarely the ring back in between.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// `RING` and `+CLIP` repeat every few seconds until a call is answered or given up
+const RING_REPEAT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Knocks {
+    calls: HashMap<String, Vec<Instant>>,
+    /// The number that is calling, and when it last rang
+    ringing: Option<(String, Instant)>,
+}
+
+impl Knocks {
+    /// Note a ring from `number`, returning whether it starts a new call, rather than repeating
+    /// for the current one
+    pub fn ring(&mut self, number: &str) -> bool {
+        let now = Instant::now();
+        let repeat = match self.ringing {
+            Some((ref ringing, last)) => ringing == number && now - last < RING_REPEAT,
+            None => false,
+        };
+        self.ringing = Some((number.to_owned(), now));
+        !repeat
+    }
+
+    /// Count a new call from `number`, forgetting calls that started `window` or longer ago
+    pub fn knock(&mut self, number: &str, window: Duration) {
+        let now = Instant::now();
+        self.calls.retain(|_, calls| {
+            calls.retain(|call| now - *call < window);
+            !calls.is_empty()
+        });
+        self.calls.entry(number.to_owned()).or_default().push(now);
+    }
+
+    /// When the calls from `number` that `ring` still remembers started
+    pub fn calls(&self, number: &str) -> &[Instant] {
+        self.calls.get(number).map_or(&[], Vec::as_slice)
+    }
+
+    /// The call ended, so that the next ring is a new call
+    pub fn hangup(&mut self) {
+        self.ringing = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_knock() {
+        let window = Duration::from_secs(20);
+        let mut knocks = Knocks::default();
+        assert!(knocks.ring("32470000001"));
+        knocks.knock("32470000001", window);
+        // RING repeats while the same call goes on
+        assert!(!knocks.ring("32470000001"));
+        knocks.hangup();
+        assert!(knocks.ring("32470000001"));
+        knocks.knock("32470000001", window);
+        assert!(knocks.ring("32470000002"));
+        knocks.knock("32470000002", window);
+        assert_eq!(knocks.calls("32470000001").len(), 2);
+
+        // Without knock rules, only the current call is remembered
+        knocks.knock("32470000001", Duration::from_secs(0));
+        assert_eq!(knocks.calls("32470000001").len(), 1);
+        assert!(knocks.calls("32470000002").is_empty());
+    }
+}
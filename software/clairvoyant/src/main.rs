@@ -1,18 +1,56 @@
+use crate::balance::BalanceMonitor;
 use crate::blink::Blinky;
+use crate::clock::Clock;
+use crate::config::{Config, TlsConfig};
+use crate::keypad::Keypad;
+use crate::knock::Knocks;
+use crate::modem::transport::{TransportConfig, TtyConfig};
+use crate::notify::exec::ExecNotifier;
+use crate::notify::mqtt::MqttNotifier;
+use crate::notify::webhook::WebhookNotifier;
+use crate::notify::Notifiers;
+use crate::outbox::Outbox;
+use crate::pending::PendingList;
+use crate::pin::Pin;
+use crate::privacy::Privacy;
+use crate::push::WhitelistPush;
+use crate::ratelimit::RateLimiter;
+use crate::remote::Verifier;
+use crate::status::Status;
 use crate::whitelist::Whitelist;
-use failure::Error;
+use failure::{format_err, Error};
 use failure::_core::time::Duration;
-use rppal::gpio::Gpio;
-use slog::{o, Drain, Logger};
+use rppal::gpio::{Gpio, Level, Trigger};
+use slog::{o, warn, Drain, Logger};
 use std::borrow::Cow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
+mod balance;
 mod blink;
+mod clock;
+mod config;
+mod control;
+mod discovery;
 mod event;
+mod keypad;
+mod knock;
 mod mainloop;
 mod modem;
+mod notify;
+mod outbox;
+mod pending;
+mod pin;
+mod privacy;
+mod push;
+mod ratelimit;
+mod remote;
+mod report;
+mod state;
+mod status;
 mod timer;
 mod whitelist;
 
@@ -28,6 +66,14 @@ struct Options {
     modem_port: PathBuf,
     #[structopt(short = "j", long = "use-journald")]
     use_journald: bool,
+    #[structopt(short = "c", long = "config")]
+    config: Option<PathBuf>,
+    /// Run without GPIO, e.g. against the simulated modem
+    #[structopt(long = "no-gpio")]
+    no_gpio: bool,
+    /// Record all traffic to and from the modem to this file, for later replay
+    #[structopt(long = "record")]
+    record: Option<PathBuf>,
 }
 
 fn init_logger(journald: bool) -> Logger {
@@ -42,50 +88,302 @@ fn init_logger(journald: bool) -> Logger {
     slog::Logger::root(drain.ignore_res(), o!())
 }
 
+/// paho only reports a failed handshake, so check the files up front
+fn ssl_options(tls: &TlsConfig) -> Result<paho_mqtt::SslOptions, Error> {
+    let path = |path: &Path| -> Result<PathBuf, Error> {
+        std::fs::metadata(path).map_err(|err| format_err!("{}: {}", path.display(), err))?;
+        Ok(path.to_owned())
+    };
+    let mut ssl = paho_mqtt::SslOptionsBuilder::new();
+    ssl.enable_server_cert_auth(tls.verify())
+        .verify(tls.verify_hostname());
+    if let Some(ref ca) = tls.ca {
+        ssl.trust_store(path(ca)?)?;
+    }
+    if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+        ssl.key_store(path(cert)?)?;
+        ssl.private_key(path(key)?)?;
+    }
+    if let Some(ref ciphers) = tls.ciphers {
+        ssl.enabled_cipher_suites(ciphers.as_str());
+    }
+    Ok(ssl.finalize())
+}
+
 fn main() -> Result<(), Error> {
     let options: Options = StructOpt::from_args();
-    let gpio = Gpio::new()?;
+    let gpio = if options.no_gpio {
+        None
+    } else {
+        Some(Gpio::new()?)
+    };
+    let output = |bcm| -> Result<Pin, Error> {
+        Ok(match gpio {
+            Some(ref gpio) => Pin::Gpio(gpio.get(bcm)?.into_output()),
+            None => Pin::Absent,
+        })
+    };
 
     let (chan_snd, chan_rcv) = channel();
 
     let logger = init_logger(options.use_journald);
 
-    let modem = modem::Modem::new(
-        &options.modem_port,
+    let config = match options.config {
+        Some(ref path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let transport = match config.modem {
+        Some(ref transport) => transport.clone(),
+        None => TransportConfig::Tty(TtyConfig::new(&options.modem_port)),
+    };
+    let privacy = Privacy::new(&config.privacy)?;
+    let mut port = transport.open()?;
+    if let Some(ref path) = options.record {
+        let mut recorder = modem::record::Recorder::new(
+            port,
+            path,
+            logger.new(o! {
+                "component" => "recorder",
+            }),
+        )?;
+        if privacy.hides_numbers() {
+            recorder.hide_callers();
+        }
+        port = Box::new(recorder);
+    }
+    let mut modem = modem::Modem::new(
+        port,
         chan_snd.clone(),
-        gpio.get(17)?.into_output(),
+        output(17)?,
         logger.new(o! {
             "component" => "modem",
         }),
     )?;
+    if let Some(ref pin) = config.sim.pin {
+        modem.unlock_with(pin);
+    }
+    if let Some(ref balance) = config.balance {
+        modem.check_balance(&balance.code, balance.interval());
+    }
+    if privacy.hides_numbers() {
+        modem.hide_callers();
+    }
 
-    let mqtt = if let Some(server_uri) = options.server {
-        let mqtt = paho_mqtt::Client::new(server_uri)?;
-        mqtt.connect(
-            paho_mqtt::ConnectOptionsBuilder::new()
-                .clean_session(true)
-                .will_message(paho_mqtt::Message::new_retained("zuul/online", "false", 0))
-                .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(32))
-                .finalize(),
-        )?;
-        mqtt
+    let remote = match config.commands {
+        Some(ref commands) => Some(
+            Verifier::new(commands.secret()?, commands.max_age())
+                .with_diagnostics(commands.diagnostics),
+        ),
+        None => None,
+    };
+
+    let push = match config.whitelist_push {
+        Some(ref push) => Some(WhitelistPush::new(
+            &push.unit,
+            &push.public_key()?,
+            push.state().to_owned(),
+            &logger,
+        )?),
+        None => None,
+    };
+
+    let server_uri = match (options.server, &config.mqtt) {
+        (Some(server_uri), _) => Some(server_uri),
+        (None, Some(mqtt)) if mqtt.enable => Some(mqtt.uri()),
+        (None, _) => None,
+    };
+    let mut commands = None;
+    let mut mqtt_retry = None;
+    let mqtt = if let Some(server_uri) = server_uri {
+        let mut create = paho_mqtt::CreateOptionsBuilder::new().server_uri(server_uri);
+        let mut connect = paho_mqtt::ConnectOptionsBuilder::new();
+        connect
+            .clean_session(true)
+            .will_message(paho_mqtt::Message::new_retained("zuul/online", "false", 0))
+            .connect_timeout(Duration::from_secs(5))
+            .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(32));
+        if let Some(ref mqtt) = config.mqtt {
+            create = create.client_id(mqtt.client_id.as_str());
+            if let Some(ref username) = mqtt.username {
+                connect.user_name(username.as_str());
+            }
+            if let Some(password) = mqtt.password()? {
+                connect.password(password);
+            }
+            if let Some(ref tls) = mqtt.tls {
+                connect.ssl_options(ssl_options(tls)?);
+            }
+        }
+        let mut mqtt = paho_mqtt::Client::new(create.finalize())?;
+        // Publishing waits for the broker's acknowledgement, which must not stall the door
+        mqtt.set_timeout(Duration::from_secs(5));
+        // Consume before connecting, so that no command gets lost
+        let ha_remote_open = match config.home_assistant {
+            Some(ref home_assistant) => home_assistant.remote_open,
+            None => false,
+        };
+        if remote.is_some() || ha_remote_open || push.is_some() {
+            commands = Some(mqtt.start_consuming());
+        }
+        // Automatic reconnects only start after a first connect, so until then the main loop
+        // keeps trying. Events are kept in the outbox meanwhile, if there is one.
+        if let Err(err) = mqtt.connect(connect.finalize()) {
+            warn!(logger, "Failed to connect to the MQTT broker"; "error" => %err);
+            mqtt_retry = Some(std::time::Instant::now() + mainloop::MQTT_RETRY);
+        }
+        Some(Rc::new(mqtt))
     } else {
-        paho_mqtt::Client::new(String::new())?
+        None
+    };
+
+    // The interrupt is only delivered while the pin is held
+    let _ring_indicator = match (&config.ring_indicator, &gpio) {
+        (Some(ri), Some(gpio)) => {
+            let (level_snd, level) = channel();
+            let waker = modem.waker();
+            let mut pin = gpio.get(ri.pin)?.into_input_pullup();
+            // RI is active low
+            pin.set_async_interrupt(Trigger::Both, move |value| {
+                level_snd
+                    .send((std::time::Instant::now(), value == Level::Low))
+                    .ok();
+                waker.wake();
+            })?;
+            modem.watch_ring_indicator(level);
+            Some(pin)
+        }
+        (Some(_), None) => {
+            warn!(logger, "Not watching RI without GPIO");
+            None
+        }
+        (None, _) => None,
+    };
+
+    let pending = match config.pending {
+        Some(ref pending) => Some(Arc::new(Mutex::new(PendingList::load(
+            pending.path(),
+            &logger,
+        )?))),
+        None => None,
+    };
+    let approvals = match pending {
+        Some(ref pending) => Some(control::Approvals {
+            pending: pending.clone(),
+            whitelist: if push.is_some() {
+                None
+            } else {
+                Some(options.whitelist_filename.clone())
+            },
+            events: chan_snd.clone(),
+            privacy: privacy.clone(),
+        }),
+        None => None,
     };
+    if let Err(err) = control::spawn(
+        config.sockets.control(),
+        modem.requests(),
+        approvals,
+        logger.new(o! {
+            "component" => "control",
+        }),
+    ) {
+        warn!(logger, "Control socket unavailable"; "error" => %err);
+    }
+
+    let mut notifiers = Notifiers::default();
+    if let Some(ref mqtt) = mqtt {
+        let outbox = match config.outbox {
+            Some(ref outbox) => Some(Outbox::load(outbox.path(), outbox.capacity(), &logger)?),
+            None => None,
+        };
+        notifiers.add(
+            Box::new(MqttNotifier::new(
+                Rc::clone(mqtt),
+                outbox,
+                logger.new(o! {
+                    "component" => "mqtt",
+                }),
+            )),
+            config
+                .mqtt
+                .as_ref()
+                .map(|mqtt| mqtt.events.clone())
+                .unwrap_or_default(),
+        );
+    }
+    for webhook in &config.notify.webhook {
+        let notifier = WebhookNotifier::new(
+            &webhook.url,
+            webhook.timeout(),
+            logger.new(o! {
+                "component" => "webhook",
+            }),
+        )?;
+        notifiers.add(Box::new(notifier), webhook.events.clone());
+    }
+    for exec in &config.notify.exec {
+        let notifier = ExecNotifier::new(
+            exec.program.clone(),
+            exec.args.clone(),
+            logger.new(o! {
+                "component" => "exec",
+            }),
+        );
+        notifiers.add(Box::new(notifier), exec.events.clone());
+    }
 
+    let modem_requests = modem.requests();
     let modem_thread = modem.spawn()?;
     timer::timer(chan_snd);
 
+    let whitelist = Whitelist::new(options.whitelist_filename)?;
+    // A lockdown lasts until it is lifted, restarts included
+    let lockdown_state = config
+        .commands
+        .as_ref()
+        .map(|commands| commands.lockdown_state().to_owned());
+    let lockdown = matches!(lockdown_state, Some(ref path) if path.exists());
+    let mut status = Status::new(whitelist.rule_count());
+    status.lockdown = lockdown;
     mainloop::MainLoop {
         event_chan: chan_rcv,
         logger,
-        gpio_door: gpio.get(27)?.into_output(),
+        gpio_door: output(27)?,
         mqtt: mqtt,
-        rpi_ok: Blinky::new(gpio.get(22)?.into_output(), Cow::Borrowed(blink::PAT_OFF)),
-        gsm_ok: Blinky::new(gpio.get(23)?.into_output(), Cow::Borrowed(blink::PAT_OFF)),
-        whitelist: Whitelist::new(options.whitelist_filename)?,
+        mqtt_retry,
+        notifiers,
+        rpi_ok: Blinky::new(output(22)?, Cow::Borrowed(blink::PAT_OFF)),
+        gsm_ok: Blinky::new(output(23)?, Cow::Borrowed(blink::PAT_OFF)),
+        status,
+        whitelist,
+        balance: config
+            .balance
+            .as_ref()
+            .map(BalanceMonitor::new)
+            .transpose()?,
+        clock: Clock::default(),
+        unknown_time: config.clock.unknown_time,
+        inventory: None,
+        modem: modem_requests,
+        caller_id: config.caller_id.clone(),
+        keypad: config.keypad.as_ref().map(Keypad::new),
+        knocks: Knocks::default(),
+        limiter: RateLimiter::new(&config.rate_limit),
+        limited: false,
+        rate_limit_alerts: config.rate_limit.alert(),
+        pending,
+        remote,
+        commands,
+        diagnostics: Vec::new(),
+        lockdown,
+        lockdown_state,
+        feedback: config.feedback.clone(),
+        privacy,
+        home_assistant: config.home_assistant.clone(),
+        push,
     }
-    .run();
+    .run()?;
 
     modem_thread
         .join()
@@ -1,35 +1,130 @@
 use std::borrow::Cow;
-use std::sync::mpsc::Receiver;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use chrono::{DateTime, Local};
 use embedded_hal::digital::v2::OutputPin;
+use failure::{format_err, Error};
 use paho_mqtt::Client as MqttClient;
-use slog::{warn, Logger};
+use slog::{info, warn, Logger};
 
+use crate::balance::BalanceMonitor;
 use crate::blink::Blinky;
-use crate::event::{Event, Regstate};
-use crate::whitelist::{MatchContext, Whitelist};
+use crate::clock::{Clock, UnknownTimePolicy};
+use crate::config::{CallerIdConfig, FeedbackConfig, HomeAssistantConfig};
+use crate::discovery;
+use crate::event::{CallerId, Event, Inventory, ModemState, Regstate};
+use crate::keypad::Keypad;
+use crate::knock::Knocks;
+use crate::modem::{Request, Response};
+use crate::notify::Notifiers;
+use crate::pending::{PendingCaller, PendingList};
+use crate::privacy::Privacy;
+use crate::push::WhitelistPush;
+use crate::ratelimit::{Limit, RateLimiter};
+use crate::remote::{self, Diagnostic, RemoteCommand, Verifier};
+use crate::report::{denial_reason, limit_reason, Report, Source};
+use crate::status::{LastDecision, Status};
+use crate::whitelist::{Decision, MatchContext, Whitelist};
+
+/// How often the status is published even if nothing changed, so that the uptime stays current
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// How often to try reaching a broker that was unreachable at startup
+pub const MQTT_RETRY: std::time::Duration = std::time::Duration::from_secs(30);
 
 pub struct MainLoop<DP: OutputPin> {
     pub event_chan: Receiver<Event>,
     pub logger: Logger,
     pub gpio_door: DP,
-    pub mqtt: MqttClient,
+    /// For commands and the connection state, None if no broker is configured. Everything
+    /// published goes through `notifiers`.
+    pub mqtt: Option<Rc<MqttClient>>,
+    /// When to try connecting again, until the broker has been reached once; after that, the
+    /// client reconnects by itself
+    pub mqtt_retry: Option<Instant>,
+    pub notifiers: Notifiers,
     pub rpi_ok: Blinky<'static, DP>,
     pub gsm_ok: Blinky<'static, DP>,
 
     pub whitelist: Whitelist,
+    pub balance: Option<BalanceMonitor>,
+    pub clock: Clock,
+    pub unknown_time: UnknownTimePolicy,
+    pub inventory: Option<Inventory>,
+    pub modem: Sender<Request>,
+    pub caller_id: CallerIdConfig,
+    pub keypad: Option<Keypad>,
+    pub knocks: Knocks,
+    pub limiter: RateLimiter,
+    /// Whether the limiter turned away the call that is ringing
+    pub limited: bool,
+    /// Publish rate limit alerts
+    pub rate_limit_alerts: bool,
+    /// Unknown callers waiting for approval, shared with the control socket
+    pub pending: Option<Arc<Mutex<PendingList>>>,
+    pub status: Status,
+    /// Checks commands received over MQTT, if they are accepted at all
+    pub remote: Option<Verifier>,
+    /// Messages on the command topics; paho messages can't leave this thread
+    pub commands: Option<Receiver<Option<paho_mqtt::Message>>>,
+    /// Remote diagnostic queries the modem has yet to answer
+    pub diagnostics: Vec<Diagnostic>,
+    /// Turn every caller away and refuse remote opens, as ordered over MQTT
+    pub lockdown: bool,
+    /// Exists while in lockdown
+    pub lockdown_state: Option<PathBuf>,
+    /// Answer calls to tell the caller what was decided
+    pub feedback: Option<FeedbackConfig>,
+    /// How numbers are shown on MQTT and in the logs
+    pub privacy: Privacy,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    /// Accepts signed whitelists from `zuul/<unit>/whitelist`
+    pub push: Option<WhitelistPush>,
 }
 
 impl<DP: OutputPin> MainLoop<DP> {
-    pub fn run(&mut self) {
+    /// Handle events until the modem is lost
+    pub fn run(&mut self) -> Result<(), Error> {
         use crate::blink;
-        use std::time::{Duration, Instant};
+        use std::time::Duration;
         let mut last_gsm_ok = Instant::now() - Duration::from_secs(1000);
         let mut gsm_notok = true;
         let mut blink_pat = Cow::Borrowed(blink::PAT_OFF);
+        let mut mqtt_connected = false;
+        let mut last_status = Instant::now();
         while let Ok(event) = self.event_chan.recv() {
             match event {
-                Event::Ring(number) => self.handle_call(number),
+                Event::Ring(caller) => self.handle_call(caller),
+                Event::Dtmf(key) => self.handle_dtmf(key),
+                Event::Hangup => {
+                    self.knocks.hangup();
+                    if let Some(ref mut keypad) = self.keypad {
+                        keypad.end();
+                    }
+                }
+                Event::WhitelistChanged => {
+                    self.reload_whitelist();
+                    self.publish_pending();
+                }
+                Event::PendingChanged => self.publish_pending(),
+                Event::Ussd(reply) => self.handle_ussd(reply),
+                Event::Inventory(inventory) => self.handle_inventory(inventory),
+                Event::NetworkTime(time) => {
+                    let old_source = self.clock.source();
+                    let offset = self.clock.set_network_time(time);
+                    let source = self.clock.source();
+                    if source != old_source {
+                        info!(self.logger, "Clock source changed";
+                              "source" => format!("{:?}", source),
+                              "offset" => offset.num_seconds());
+                    }
+                }
                 Event::Creg(regstate) => {
                     blink_pat = Cow::Borrowed(match regstate {
                         Regstate::Unregistered => blink::PAT_OFF,
@@ -45,7 +140,37 @@ impl<DP: OutputPin> MainLoop<DP> {
                     last_gsm_ok = Instant::now();
                     self.gsm_ok.change_pattern(blink_pat.clone());
                     gsm_notok = false;
+                    if self.status.registration.as_ref() != Some(&regstate) {
+                        self.status.registration = Some(regstate);
+                        self.publish_status();
+                    }
+                }
+                Event::ModemState(state) => {
+                    if state == ModemState::Fault || state == ModemState::SimLocked {
+                        blink_pat = Cow::Borrowed(blink::PAT_SOS);
+                        self.gsm_ok.change_pattern(blink_pat.clone());
+                    }
+                    if state == ModemState::SimLocked {
+                        let report = Report::new("sim-locked", self.clock.now());
+                        self.publish_report("zuul/alert/modem", &report);
+                    }
+                    self.status.modem = Some(state);
+                    self.publish_status();
                 }
+                Event::Signal(rssi) => {
+                    if self.status.signal != rssi {
+                        self.status.signal = rssi;
+                        self.publish_status();
+                    }
+                }
+                Event::Diagnostic(diagnostic) => {
+                    let diagnostic = format!("{:?}", diagnostic);
+                    warn!(self.logger, "Modem diagnostic"; "diagnostic" => &diagnostic);
+                    let report =
+                        Report::new("modem-diagnostic", self.clock.now()).with_reason(&diagnostic);
+                    self.publish_report("zuul/alert/modem", &report);
+                }
+                Event::ModemLost(reason) => return Err(format_err!("Lost the modem: {}", reason)),
                 Event::GsmOk => {
                     last_gsm_ok = Instant::now();
                     if gsm_notok {
@@ -53,6 +178,29 @@ impl<DP: OutputPin> MainLoop<DP> {
                     }
                 }
                 Event::Heartbeat => {
+                    // The will marks us offline whenever the connection drops, so say otherwise
+                    // each time it comes back
+                    let connected = self.mqtt_connected();
+                    if connected && (!mqtt_connected || last_status.elapsed() > STATUS_INTERVAL) {
+                        self.notifiers.publish("zuul/online", "true", 0, true);
+                        if !mqtt_connected {
+                            self.subscribe_commands();
+                            self.publish_discovery();
+                        }
+                        self.publish_status();
+                        last_status = Instant::now();
+                    }
+                    mqtt_connected = connected;
+                    self.retry_mqtt(connected);
+                    self.notifiers.heartbeat();
+                    self.poll_remote();
+                    self.poll_diagnostics();
+                    if let Some(ref mut keypad) = self.keypad {
+                        if keypad.expire() {
+                            info!(self.logger, "Keypad entry timed out");
+                            self.modem_cmd("ATH");
+                        }
+                    }
                     if last_gsm_ok.elapsed() > Duration::from_secs(30) {
                         self.gsm_ok.change_pattern(Cow::Borrowed(blink::PAT_OFF));
                         gsm_notok = true;
@@ -62,18 +210,746 @@ impl<DP: OutputPin> MainLoop<DP> {
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn handle_call(&mut self, number: String) {
-        use paho_mqtt::Message;
-        self.mqtt
-            .publish(Message::new("zuul/ring", number.as_bytes(), 0))
+    /// Queue a command on the modem, without waiting for its response
+    fn modem_cmd(&self, cmd: &str) {
+        let (reply, _) = channel();
+        self.modem
+            .send(Request {
+                cmd: cmd.to_owned(),
+                reply,
+            })
             .ok();
+    }
+
+    /// Play the tones for `decision` to the caller, and hang up
+    fn play_tones(&self, decision: &Decision) {
+        let default = FeedbackConfig::default();
+        let tones = self.feedback.as_ref().unwrap_or(&default).tones(decision);
+        self.modem_cmd(&format!("AT+VTS=\"{}\"", tones));
+        self.modem_cmd("ATH");
+    }
+
+    fn open_door(&self, report: &Report) {
+        info!(self.logger, "Opening door";
+              "label" => report.label.unwrap_or("anon"), "source" => report.source.map(Source::name));
+        self.publish_report("zuul/open", report);
+    }
+
+    /// `number` as it may be published or logged
+    fn shown(&self, number: &str) -> String {
+        self.privacy.show(number, self.whitelist.lists(number))
+    }
+
+    /// Hand an event to the notifiers, e.g. the broker
+    fn publish_report(&self, topic: &str, report: &Report) {
+        if let Err(err) = self.notifiers.notify(topic, report) {
+            warn!(self.logger, "Failed to serialize report"; "error" => %err);
+        }
+    }
+
+    pub fn handle_call(&mut self, caller: CallerId) {
+        // The caller is entering a PIN; RING repeats until the call is answered
+        if let Some(ref keypad) = self.keypad {
+            if keypad.in_call_with(&caller.number) {
+                return;
+            }
+        }
+        let number = caller.number;
+        let shown = self.shown(&number);
+        let now = self.clock.now();
+        let new_call = self.knocks.ring(&number);
+        if new_call {
+            let listed = self.whitelist.lists(&number);
+            self.limited = match self.limiter.call(&number, listed) {
+                Ok(()) => false,
+                Err(limit) => {
+                    self.handle_limit(&shown, limit, now);
+                    true
+                }
+            };
+            if !self.limited {
+                self.knocks.knock(&number, self.whitelist.knock_window());
+            }
+        }
+        if self.limited {
+            return;
+        }
+        if new_call {
+            self.publish_report("zuul/ring", &Report::new("ring", now).with_number(&shown));
+        }
+
+        let trusted = self.caller_id.trusts(caller.validity);
+        if let Some(reason) = caller.validity.reason() {
+            info!(self.logger, "Caller ID is not verified";
+                  "number" => &shown, "reason" => reason, "trusted" => trusted);
+        }
+
+        if now.is_none() {
+            warn!(self.logger, "Current time is unknown";
+                  "policy" => format!("{:?}", self.unknown_time));
+        }
+        if self.lockdown {
+            warn!(self.logger, "Turning caller away during lockdown"; "number" => &shown);
+        }
+        let admissible = trusted && !self.lockdown;
+        let ctx = MatchContext::new(&number, now, self.unknown_time)
+            .with_calls(self.knocks.calls(&number));
+        let (decision, rule) = if admissible {
+            self.whitelist.decide(&ctx)
+        } else {
+            (Decision::Deny, None)
+        };
+        if new_call {
+            self.status.last_decision = Some(LastDecision::new(&decision, now));
+            self.publish_status();
+            if decision == Decision::Deny && admissible && !number.is_empty() {
+                self.record_pending(&number, &shown, now);
+            }
+        }
+        let report = |event| {
+            Report::new(event, now)
+                .with_number(&shown)
+                .with_decision(&decision, rule)
+        };
+        let accepted = matches!(decision, Decision::Accept(_));
+        // A PIN is as good from any number, so the keypad doesn't need the number to be verified;
+        // lockouts are shared between the callers whose number isn't
+        let offer_keypad = !self.lockdown && decision != Decision::AwaitingKnock;
+        if accepted {
+            self.open_door(&report("open").with_source(Source::Call));
+        } else if let (true, Some(ref mut keypad)) = (offer_keypad, &mut self.keypad) {
+            if keypad.is_shut() {
+                warn!(self.logger, "Keypad is shut after too many wrong PINs"; "number" => &shown);
+            } else if keypad.is_locked_out(&number, trusted) {
+                warn!(self.logger, "Caller is locked out of the keypad"; "number" => &shown);
+            } else if keypad.in_call() {
+                info!(self.logger, "Keypad is busy with another caller"; "number" => &shown);
+            } else {
+                info!(self.logger, "Answering for keypad entry"; "number" => &shown);
+                keypad.start(number.clone(), trusted);
+                self.modem_cmd("ATA");
+                self.modem_cmd("AT+DDET=1");
+                return;
+            }
+        }
+        if new_call && !accepted {
+            let reason = if self.lockdown {
+                "lockdown"
+            } else if !trusted {
+                "untrusted-caller-id"
+            } else {
+                denial_reason(&decision)
+            };
+            self.publish_report("zuul/denied", &report("denied").with_reason(reason));
+        }
+        if decision == Decision::AwaitingKnock {
+            // Answering would keep them from calling again
+            info!(self.logger, "Waiting for the caller to knock"; "number" => &shown);
+            return;
+        }
+
+        if self.feedback.is_some() {
+            self.modem_cmd("ATA");
+            self.play_tones(&decision);
+        }
+    }
+
+    fn reload_whitelist(&mut self) {
+        match self.whitelist.reload() {
+            Ok(()) => {
+                info!(self.logger, "Whitelist reloaded"; "rules" => self.whitelist.rule_count())
+            }
+            Err(err) => warn!(self.logger, "Failed to reload whitelist"; "error" => %err),
+        }
+        self.status.whitelist_rules = self.whitelist.rule_count();
+        self.publish_status();
+    }
+
+    fn mqtt_connected(&self) -> bool {
+        matches!(self.mqtt, Some(ref mqtt) if mqtt.is_connected())
+    }
+
+    /// Connect to a broker that could not be reached at startup
+    fn retry_mqtt(&mut self, connected: bool) {
+        if connected {
+            self.mqtt_retry = None;
+        }
+        match (&self.mqtt, self.mqtt_retry) {
+            (Some(mqtt), Some(retry)) if retry <= Instant::now() => {
+                if let Err(err) = mqtt.reconnect() {
+                    warn!(self.logger, "Failed to connect to the MQTT broker"; "error" => %err);
+                    self.mqtt_retry = Some(Instant::now() + MQTT_RETRY);
+                }
+            }
+            _ => {}
+        }
+    }
 
-        if let Some(label) = self.whitelist.matches(&MatchContext::new(&number)) {
-            self.mqtt
-                .publish(Message::new("zuul/open", label.unwrap_or("anon"), 0))
-                .ok();
+    /// Subscriptions don't survive a clean session, so this is done on every connect
+    fn subscribe_commands(&self) {
+        let mut topics = Vec::new();
+        if self.remote.is_some() {
+            topics.push("zuul/cmd/+");
         }
+        let open_topic = self.ha_open_topic();
+        if let Some(ref open_topic) = open_topic {
+            topics.push(open_topic);
+        }
+        if let Some(ref push) = self.push {
+            topics.push(push.topic());
+        }
+        let mqtt = match self.mqtt {
+            Some(ref mqtt) => mqtt,
+            None => return,
+        };
+        for topic in topics {
+            if let Err(err) = mqtt.subscribe(topic, 1) {
+                warn!(self.logger, "Failed to subscribe to commands"; "topic" => topic, "error" => %err);
+            }
+        }
+    }
+
+    fn ha_open_topic(&self) -> Option<String> {
+        let home_assistant = self.home_assistant.as_ref()?;
+        home_assistant.open_token().map(discovery::open_topic)
+    }
+
+    /// Publish the Home Assistant discovery configs, once the IMEI identifies the device
+    fn publish_discovery(&self) {
+        let (home_assistant, inventory) = match (&self.home_assistant, &self.inventory) {
+            (Some(home_assistant), Some(inventory)) => (home_assistant, inventory),
+            _ => return,
+        };
+        let configs = discovery::configs(
+            home_assistant.prefix(),
+            inventory,
+            home_assistant.open_token(),
+        );
+        for (topic, config) in configs.unwrap_or_default() {
+            self.notifiers.publish(&topic, &config.to_string(), 0, true);
+        }
+    }
+
+    fn poll_remote(&mut self) {
+        let mut received = Vec::new();
+        if let Some(ref commands) = self.commands {
+            // None means the connection was lost; messages resume once it is back
+            while let Ok(message) = commands.try_recv() {
+                received.extend(message);
+            }
+        }
+        for message in received {
+            if Some(message.topic()) == self.ha_open_topic().as_deref() {
+                // A retained press would open the door again on every connect
+                if message.retained() || message.payload() != discovery::OPEN_PAYLOAD.as_bytes() {
+                    warn!(self.logger, "Ignoring Home Assistant open";
+                          "retained" => message.retained());
+                } else if self.lockdown {
+                    // There is no one to reply to, so the denial is the answer
+                    warn!(
+                        self.logger,
+                        "Turning Home Assistant open away during lockdown"
+                    );
+                    let report = Report::new("denied", self.clock.now())
+                        .with_source(Source::HomeAssistant)
+                        .with_reason("lockdown");
+                    self.publish_report("zuul/denied", &report);
+                } else {
+                    let report =
+                        Report::new("open", self.clock.now()).with_source(Source::HomeAssistant);
+                    self.open_door(&report);
+                }
+            } else if self.push.as_ref().map(WhitelistPush::topic) == Some(message.topic()) {
+                self.handle_push(message.payload());
+            } else {
+                self.handle_remote(message.topic(), message.payload());
+            }
+        }
+    }
+
+    pub fn handle_remote(&mut self, topic: &str, payload: &[u8]) {
+        let verifier = match self.remote {
+            Some(ref mut verifier) => verifier,
+            None => return,
+        };
+        // Without a trustworthy clock, there is no telling an old command from a new one
+        let verified = match self.clock.now() {
+            Some(now) => verifier.verify(topic, payload, now.timestamp()),
+            None => Err("Current time is unknown".to_owned()),
+        };
+        let reply = match verified {
+            Ok((nonce, RemoteCommand::Diagnose(query))) => {
+                info!(self.logger, "Remote diagnostic"; "query" => &query);
+                let (reply, response) = channel();
+                let request = Request {
+                    cmd: query.clone(),
+                    reply,
+                };
+                if self.modem.send(request).is_ok() {
+                    // Replied to once the modem has answered; see poll_diagnostics
+                    self.diagnostics.push(Diagnostic {
+                        nonce,
+                        query,
+                        response,
+                    });
+                    return;
+                }
+                serde_json::json!({ "nonce": nonce, "ok": false, "error": "Modem is not running" })
+            }
+            Ok((nonce, command)) => {
+                // Approvals carry a number, which the privacy settings may keep out of the log
+                let name = topic.rsplit('/').next().unwrap_or_default();
+                info!(self.logger, "Remote command"; "command" => name);
+                match self.run_remote(command) {
+                    Ok(()) => serde_json::json!({ "nonce": nonce, "ok": true }),
+                    Err(error) => {
+                        warn!(self.logger, "Remote command failed"; "error" => &error);
+                        serde_json::json!({ "nonce": nonce, "ok": false, "error": error })
+                    }
+                }
+            }
+            Err(error) => {
+                warn!(self.logger, "Rejected remote command"; "topic" => topic, "error" => &error);
+                serde_json::json!({ "ok": false, "error": error })
+            }
+        };
+        let name = topic.rsplit('/').next().unwrap_or_default();
+        self.notifiers.publish(
+            &format!("zuul/reply/{}", name),
+            &reply.to_string(),
+            1,
+            false,
+        );
+    }
+
+    /// Apply a whitelist pushed over MQTT, and say which one is in effect
+    fn handle_push(&mut self, payload: &[u8]) {
+        let push = match self.push {
+            Some(ref mut push) => push,
+            None => return,
+        };
+        let topic = push.topic().to_owned();
+        let applied = match push.verify(payload) {
+            Ok(Some(pushed)) => match self.whitelist.replace(&pushed.whitelist) {
+                Ok(()) => {
+                    if let Err(err) = push.applied(pushed.applied.clone()) {
+                        warn!(self.logger, "Failed to save pushed whitelist state"; "error" => %err);
+                    }
+                    Ok(pushed.applied)
+                }
+                Err(err) => Err(format!("Failed to apply whitelist: {}", err)),
+            },
+            // Already in effect
+            Ok(None) => return,
+            Err(error) => Err(error),
+        };
+        match applied {
+            Ok(applied) => {
+                let rules = self.whitelist.rule_count();
+                info!(self.logger, "Applied pushed whitelist";
+                      "hash" => &applied.hash, "issued" => applied.issued, "rules" => rules);
+                let document = serde_json::json!({
+                    "issued": applied.issued,
+                    "hash": applied.hash,
+                    "rules": rules,
+                });
+                self.notifiers.publish(
+                    &format!("{}/applied", topic),
+                    &document.to_string(),
+                    1,
+                    true,
+                );
+                self.status.whitelist_rules = rules;
+                self.publish_status();
+            }
+            Err(error) => {
+                warn!(self.logger, "Rejected pushed whitelist"; "error" => &error);
+                self.notifiers.publish(
+                    &format!("{}/rejected", topic),
+                    &serde_json::json!({ "error": error }).to_string(),
+                    1,
+                    false,
+                );
+            }
+        }
+    }
+
+    fn run_remote(&mut self, command: RemoteCommand) -> Result<(), String> {
+        match command {
+            RemoteCommand::Open(label) => {
+                // Lockdown is lifted with its own command, not worked around
+                if self.lockdown {
+                    let report = Report::new("denied", self.clock.now())
+                        .with_label(label.as_deref())
+                        .with_source(Source::Remote)
+                        .with_reason("lockdown");
+                    self.publish_report("zuul/denied", &report);
+                    return Err("Door is in lockdown".to_owned());
+                }
+                let report = Report::new("open", self.clock.now())
+                    .with_label(label.as_deref())
+                    .with_source(Source::Remote);
+                self.open_door(&report)
+            }
+            RemoteCommand::Reload => self.reload_whitelist(),
+            RemoteCommand::Lockdown(lockdown) => {
+                self.lockdown = lockdown;
+                self.status.lockdown = lockdown;
+                self.publish_status();
+                if let (true, Some(ref mut keypad)) = (lockdown, &mut self.keypad) {
+                    if keypad.in_call() {
+                        info!(self.logger, "Ending keypad entry for lockdown");
+                        keypad.end();
+                        self.modem_cmd("ATH");
+                    }
+                }
+                if let Some(ref path) = self.lockdown_state {
+                    let saved = if lockdown {
+                        fs::write(path, "")
+                    } else {
+                        match fs::remove_file(path) {
+                            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                            other => other,
+                        }
+                    };
+                    saved.map_err(|err| format!("Failed to save lockdown: {}", err))?;
+                }
+            }
+            RemoteCommand::Approve {
+                caller,
+                label,
+                until,
+            } => {
+                let pending = self
+                    .pending
+                    .as_ref()
+                    .ok_or("Pending callers are not kept")?;
+                // The next push would drop the rule again
+                if self.push.is_some() {
+                    return Err(
+                        "Whitelist is pushed; approve callers where it is signed".to_owned()
+                    );
+                }
+                let caller = pending
+                    .lock()
+                    .unwrap()
+                    .approve(&caller, &label, until, self.whitelist.path())
+                    .map_err(|err| err.to_string())?;
+                info!(self.logger, "Approved pending caller";
+                      "number" => self.privacy.show(&caller.number, true), "label" => &label);
+                self.reload_whitelist();
+                self.publish_pending();
+            }
+            RemoteCommand::Dismiss(caller) => {
+                let pending = self
+                    .pending
+                    .as_ref()
+                    .ok_or("Pending callers are not kept")?;
+                let caller = pending
+                    .lock()
+                    .unwrap()
+                    .dismiss(&caller)
+                    .map_err(|err| err.to_string())?;
+                info!(self.logger, "Dismissed pending caller";
+                      "number" => self.privacy.show(&caller.number, false));
+                self.publish_pending();
+            }
+            // Its reply waits for the modem, so it is run by handle_remote
+            RemoteCommand::Diagnose(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Reply to the remote diagnostic queries the modem has answered
+    fn poll_diagnostics(&mut self) {
+        use std::sync::mpsc::TryRecvError;
+        for diagnostic in std::mem::take(&mut self.diagnostics) {
+            let Diagnostic { nonce, query, .. } = &diagnostic;
+            let reply = match diagnostic.response.try_recv() {
+                Ok(Response {
+                    text,
+                    result: Some(result),
+                }) => serde_json::json!({
+                    "nonce": nonce,
+                    "ok": true,
+                    "query": query,
+                    "response": text
+                        .iter()
+                        .filter(|line| remote::answers(query, line))
+                        .collect::<Vec<_>>(),
+                    "result": result,
+                }),
+                Ok(Response { result: None, .. }) => {
+                    serde_json::json!({ "nonce": nonce, "ok": false, "error": "Timed out" })
+                }
+                Err(TryRecvError::Empty) => {
+                    self.diagnostics.push(diagnostic);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    serde_json::json!({ "nonce": nonce, "ok": false, "error": "Modem is not running" })
+                }
+            };
+            self.notifiers
+                .publish("zuul/reply/at", &reply.to_string(), 1, false);
+        }
+    }
+
+    fn publish_status(&self) {
+        match self.status.to_json() {
+            Ok(document) => {
+                self.notifiers.publish("zuul/status", &document, 0, true);
+            }
+            Err(err) => warn!(self.logger, "Failed to serialize status"; "error" => %err),
+        }
+    }
+
+    fn record_pending(&self, number: &str, shown: &str, now: Option<DateTime<Local>>) {
+        if let Some(ref pending) = self.pending {
+            info!(self.logger, "Caller is pending approval"; "number" => shown);
+            let recorded = pending.lock().unwrap().record(number, now);
+            if let Err(err) = recorded {
+                warn!(self.logger, "Failed to save pending callers"; "error" => %err);
+            }
+            self.publish_pending();
+        }
+    }
+
+    /// The numbers may be hidden, so admins approve by the ids
+    fn publish_pending(&self) {
+        let pending = match self.pending {
+            Some(ref pending) => pending.lock().unwrap(),
+            None => return,
+        };
+        let callers: Vec<PendingCaller> = pending
+            .callers()
+            .iter()
+            .map(|caller| PendingCaller {
+                number: self.privacy.show(&caller.number, false),
+                ..caller.clone()
+            })
+            .collect();
+        match serde_json::to_string(&callers) {
+            Ok(document) => {
+                self.notifiers.publish("zuul/pending", &document, 0, true);
+            }
+            Err(err) => warn!(self.logger, "Failed to serialize pending callers"; "error" => %err),
+        }
+    }
+
+    fn handle_limit(&self, shown: &str, limit: Limit, now: Option<DateTime<Local>>) {
+        warn!(self.logger, "Call rate limited";
+              "number" => shown, "limit" => format!("{:?}", limit));
+        let reason = limit_reason(limit);
+        let denied = Report::new("denied", now)
+            .with_number(shown)
+            .with_reason(reason);
+        self.publish_report("zuul/denied", &denied);
+        if self.rate_limit_alerts && limit.tripped() {
+            let mut alert = Report::new("rate-limit", now).with_reason(reason);
+            if limit == Limit::Caller {
+                alert = alert.with_number(shown);
+            }
+            self.publish_report("zuul/alert/rate_limit", &alert);
+        }
+        match serde_json::to_string(&self.limiter.stats) {
+            Ok(document) => {
+                self.notifiers
+                    .publish("zuul/metrics/rate_limit", &document, 0, true);
+            }
+            Err(err) => warn!(self.logger, "Failed to serialize rate limit stats"; "error" => %err),
+        }
+    }
+
+    pub fn handle_dtmf(&mut self, key: char) {
+        let entry = match self.keypad.as_mut().and_then(|keypad| keypad.key(key)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let shown = self.shown(&entry.number);
+        if self.lockdown {
+            warn!(self.logger, "Turning keypad entry away during lockdown"; "number" => &shown);
+            let report = Report::new("denied", self.clock.now())
+                .with_number(&shown)
+                .with_reason("lockdown");
+            self.publish_report("zuul/denied", &report);
+            self.play_tones(&Decision::Deny);
+            return;
+        }
+
+        let now = self.clock.now();
+        // An unverified number only gets the rules that don't name one
+        let number = if entry.verified { &entry.number } else { "" };
+        let ctx = MatchContext::new(number, now, self.unknown_time)
+            .with_pin(&entry.pin)
+            .with_calls(self.knocks.calls(&entry.number));
+        let (decision, rule) = match self.whitelist.decide(&ctx) {
+            accepted @ (Decision::Accept(_), _) => accepted,
+            // A PIN that is right but can't be used now counts as wrong, or guessing would show
+            // which ones are right
+            (decision, _) => {
+                info!(self.logger, "Keypad PIN rejected"; "decision" => format!("{:?}", decision));
+                (Decision::Deny, None)
+            }
+        };
+        self.status.last_decision = Some(LastDecision::new(&decision, now));
+        self.publish_status();
+        let report = |event| {
+            Report::new(event, now)
+                .with_number(&shown)
+                .with_decision(&decision, rule)
+        };
+        if let Decision::Accept(_) = decision {
+            self.open_door(&report("open").with_source(Source::Keypad));
+        } else {
+            self.publish_report("zuul/denied", &report("denied").with_reason("wrong-pin"));
+        }
+        let mut locked_out = false;
+        if let Some(ref mut keypad) = self.keypad {
+            if let Decision::Accept(_) = decision {
+                keypad.succeed(&entry.number, entry.verified);
+            } else if keypad.fail(&entry.number, entry.verified) {
+                warn!(self.logger, "Wrong keypad PIN, locking out caller"; "number" => &shown);
+                locked_out = true;
+            } else {
+                warn!(self.logger, "Wrong keypad PIN"; "number" => &shown);
+            }
+        }
+        if locked_out {
+            let alert = Report::new("keypad-lockout", now).with_number(&shown);
+            self.publish_report("zuul/alert/keypad", &alert);
+        }
+        self.play_tones(&decision);
+    }
+
+    pub fn handle_inventory(&mut self, inventory: Inventory) {
+        let field = |value: &Option<String>| value.clone().unwrap_or_default();
+        info!(self.logger, "Modem inventory";
+              "manufacturer" => field(&inventory.manufacturer),
+              "model" => field(&inventory.model),
+              "firmware" => field(&inventory.firmware),
+              "imei" => field(&inventory.imei),
+              "imsi" => field(&inventory.imsi),
+              "iccid" => field(&inventory.iccid));
+        match serde_json::to_string(&inventory) {
+            Ok(document) => {
+                self.notifiers.publish("zuul/inventory", &document, 0, true);
+            }
+            Err(err) => warn!(self.logger, "Failed to serialize inventory"; "error" => %err),
+        }
+        self.inventory = Some(inventory);
+        self.publish_discovery();
+    }
+
+    pub fn handle_ussd(&mut self, reply: String) {
+        info!(self.logger, "USSD reply"; "reply" => &reply);
+
+        let balance = match self.balance {
+            Some(ref mut balance) => balance,
+            None => return,
+        };
+        match balance.extract(&reply) {
+            Some(amount) => {
+                let dropped_low = balance.dropped_low(amount);
+                self.notifiers
+                    .publish("zuul/balance", &amount.to_string(), 0, true);
+                if dropped_low {
+                    warn!(self.logger, "SIM balance is low"; "balance" => amount);
+                    let alert = Report::new("low-balance", self.clock.now()).with_balance(amount);
+                    self.publish_report("zuul/alert/balance", &alert);
+                }
+            }
+            None => warn!(self.logger, "No balance found in USSD reply"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::notify::Notifier;
+    use crate::pin::Pin;
+    use std::cell::RefCell;
+
+    /// Keeps the topic and reason of every event
+    struct Events(Rc<RefCell<Vec<String>>>);
+
+    impl Notifier for Events {
+        fn notify(&self, topic: &str, _document: &str, report: &Report) {
+            let reason = report.reason.unwrap_or_default();
+            self.0.borrow_mut().push(format!("{} {}", topic, reason));
+        }
+    }
+
+    fn main_loop(whitelist: &std::path::Path, events: &Rc<RefCell<Vec<String>>>) -> MainLoop<Pin> {
+        let mut notifiers = Notifiers::default();
+        notifiers.add(Box::new(Events(Rc::clone(events))), vec![]);
+        MainLoop {
+            event_chan: channel().1,
+            logger: Logger::root(slog::Discard, slog::o!()),
+            gpio_door: Pin::Absent,
+            mqtt: None,
+            mqtt_retry: None,
+            notifiers,
+            rpi_ok: Blinky::new(Pin::Absent, Cow::Borrowed(crate::blink::PAT_OFF)),
+            gsm_ok: Blinky::new(Pin::Absent, Cow::Borrowed(crate::blink::PAT_OFF)),
+            whitelist: Whitelist::new(whitelist).unwrap(),
+            balance: None,
+            clock: Clock::default(),
+            unknown_time: UnknownTimePolicy::default(),
+            inventory: None,
+            modem: channel().0,
+            caller_id: CallerIdConfig::default(),
+            keypad: None,
+            knocks: Knocks::default(),
+            limiter: RateLimiter::new(&Default::default()),
+            limited: false,
+            rate_limit_alerts: false,
+            pending: None,
+            status: Status::new(0),
+            remote: None,
+            commands: None,
+            diagnostics: Vec::new(),
+            lockdown: false,
+            lockdown_state: None,
+            feedback: None,
+            privacy: Privacy::default(),
+            home_assistant: None,
+            push: None,
+        }
+    }
+
+    #[test]
+    fn test_lockdown_refuses_opens() {
+        let whitelist = std::env::temp_dir().join(format!("zuul-lockdown-{}", std::process::id()));
+        fs::write(&whitelist, "").unwrap();
+        let events = Rc::default();
+        let mut main_loop = main_loop(&whitelist, &events);
+        fs::remove_file(&whitelist).unwrap();
+        main_loop.home_assistant =
+            Some(toml::from_str("remote_open = true\nopen_token = \"0123456789abcdef\"").unwrap());
+        let open_topic = main_loop.ha_open_topic().unwrap();
+        let (commands, received) = channel();
+        main_loop.commands = Some(received);
+        let press = || paho_mqtt::Message::new(open_topic.as_str(), discovery::OPEN_PAYLOAD, 1);
+
+        main_loop.lockdown = true;
+        commands.send(Some(press())).unwrap();
+        main_loop.poll_remote();
+        assert!(main_loop.run_remote(RemoteCommand::Open(None)).is_err());
+        assert_eq!(
+            *events.borrow(),
+            vec!["zuul/denied lockdown", "zuul/denied lockdown"]
+        );
+
+        events.borrow_mut().clear();
+        main_loop.lockdown = false;
+        commands.send(Some(press())).unwrap();
+        main_loop.poll_remote();
+        assert!(main_loop.run_remote(RemoteCommand::Open(None)).is_ok());
+        assert_eq!(*events.borrow(), vec!["zuul/open ", "zuul/open "]);
     }
 }
@@ -1,60 +1,330 @@
-use std::io::{prelude::*, BufRead, BufReader, Error as IoError};
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{prelude::*, BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind};
 use std::sync::mpsc;
 use std::thread;
 
 use embedded_hal::digital::v2::OutputPin;
 use lazy_static::lazy_static;
 use regex::bytes::Regex;
-use serial::prelude::*;
 
-use crate::event::{Event, Regstate};
-use slog::{debug, info, warn, Logger};
-use std::time::Duration;
+use crate::event::{CallerId, CliValidity, Diagnostic, Event, Inventory, ModemState, Regstate};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use slog::{debug, error, info, warn, Logger};
+use std::time::{Duration, Instant};
+
+pub mod record;
+pub mod transport;
+mod ussd;
+
+use transport::{Transport, Wakeable, Waker};
 
 lazy_static! {
-    static ref CREG_RE: Regex = Regex::new(r"\+CREG: *(?:\d*,)(\d+)\r\n").unwrap();
-    static ref CLIP_RE: Regex = Regex::new(r#"\+CLIP: *"([^"]+)""#).unwrap();
+    static ref CREG_RE: Regex = Regex::new(r"\+CREG: *(?:\d*,)?(\d+)\r\n").unwrap();
+    static ref CLIP_RE: Regex =
+        Regex::new(r#"\+CLIP: *"([^"]*)",(\d+)(?:,"[^"]*",\d*(?:,"([^"]*)"(?:,(\d+))?)?)?"#)
+            .unwrap();
     static ref CPIN_RE: Regex = Regex::new(r"\+CPIN: *([^\r\n]+)\r\n").unwrap();
+    static ref CCLK_RE: Regex =
+        Regex::new(r#"\+CCLK: *"(\d\d)/(\d\d)/(\d\d),(\d\d):(\d\d):(\d\d)([+-]\d\d)""#).unwrap();
+    static ref CSQ_RE: Regex = Regex::new(r"^\+CSQ: *(\d+),\d+").unwrap();
+    static ref DTMF_RE: Regex = Regex::new(r"^\+DTMF: *([0-9A-D*#])").unwrap();
+    static ref NITZ_RE: Regex = Regex::new(r"^(?:\*PSUTTZ|\+CTZV|DST):").unwrap();
+    static ref CALLER_RE: Regex =
+        Regex::new(r#"(?m)^(\+CLIP|\+CLCC)(: *[^"\r\n]*)"[^"\r\n]*""#).unwrap();
+    static ref FINAL_RE: Regex = Regex::new(r"^(?:OK|ERROR|\+CM[ES] ERROR:.*)\r\n$").unwrap();
+}
+
+/// How long to wait for a final result code before giving up on a command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to re-read the network time once NITZ has set the modem clock
+const CLOCK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often to read the signal strength while registered
+const SIGNAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How far apart RI and the URC that goes with it may be
+const RI_WINDOW: Duration = Duration::from_secs(2);
+
+/// How long the modem may stay in each state before we intervene
+fn state_timeout(state: ModemState) -> Option<Duration> {
+    match state {
+        ModemState::PoweredOff => Some(Duration::from_secs(1)),
+        ModemState::Booting => Some(Duration::from_secs(10)),
+        ModemState::SimCheck | ModemState::Unlocking => Some(Duration::from_secs(20)),
+        ModemState::Configuring => Some(Duration::from_secs(30)),
+        ModemState::Registering => Some(Duration::from_secs(180)),
+        ModemState::Ready | ModemState::SimLocked => None,
+        ModemState::Fault => Some(Duration::from_secs(30)),
+    }
 }
 
-type Port = serial_unix::TTYPort;
+/// What to do with the response to a queued command
+#[derive(Debug)]
+enum Reply {
+    Ignore,
+    /// A setup step: on success move on to the given state, if any, and fault on failure
+    Boot(Option<ModemState>),
+    /// Entering the PIN, which is never retried if it fails
+    Unlock,
+    /// Whether the modem sets its clock from the network
+    ClockSync,
+    Inventory(InventoryField),
+    /// Send the response back to whoever asked, e.g. the control socket
+    Passthrough(mpsc::Sender<Response>),
+}
+
+/// A command sent to the modem on behalf of someone else
+pub struct Request {
+    /// The command line, without terminator
+    pub cmd: String,
+    pub reply: mpsc::Sender<Response>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    /// Everything the modem said while the command ran, except the echo
+    pub text: Vec<String>,
+    /// The final result code, or None if the command timed out
+    pub result: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InventoryField {
+    Manufacturer,
+    Model,
+    Firmware,
+    Imei,
+    Imsi,
+    Iccid,
+}
+
+/// Queried once the SIM is ready. The inventory is reported after the last one completes.
+const INVENTORY_QUERIES: &[(&[u8], InventoryField)] = &[
+    (b"AT+CGMI\n", InventoryField::Manufacturer),
+    (b"AT+CGMM\n", InventoryField::Model),
+    (b"AT+CGMR\n", InventoryField::Firmware),
+    (b"AT+CGSN\n", InventoryField::Imei),
+    (b"AT+CIMI\n", InventoryField::Imsi),
+    (b"AT+CCID\n", InventoryField::Iccid),
+];
+
+struct Command {
+    cmd: Vec<u8>,
+    reply: Reply,
+}
+
+struct InFlight {
+    started: Instant,
+    reply: Reply,
+    /// Information text received so far, without line terminators
+    response: Vec<String>,
+}
+
+impl InFlight {
+    fn is_passthrough(&self) -> bool {
+        matches!(self.reply, Reply::Passthrough(_))
+    }
+
+    fn collect(&mut self, line: &[u8]) {
+        // Information text, unless it is the echo of the command or blank
+        let text = String::from_utf8_lossy(line).trim().to_owned();
+        if !text.is_empty() && !text.to_ascii_uppercase().starts_with("AT") {
+            self.response.push(text);
+        }
+    }
+}
+
+/// A periodic USSD query, used to keep an eye on the balance of a prepaid SIM
+struct UssdCheck {
+    code: String,
+    interval: Duration,
+    next: Instant,
+}
+
+/// Cross-checks the RI line against the URCs received
+struct RiCheck {
+    /// When RI changed level, and whether it is now asserted
+    level: mpsc::Receiver<(Instant, bool)>,
+    asserted: bool,
+    /// When data last arrived from the modem
+    last_data: Option<Instant>,
+    /// When RI was asserted, until some data from the modem explains it
+    pending_ri: Option<Instant>,
+    /// When a call was reported while RI was not asserted, until RI catches up
+    pending_ring: Option<Instant>,
+}
+
+impl RiCheck {
+    fn new(level: mpsc::Receiver<(Instant, bool)>) -> Self {
+        RiCheck {
+            level,
+            asserted: false,
+            last_data: None,
+            pending_ri: None,
+            pending_ring: None,
+        }
+    }
+
+    /// Take in the level changes so far
+    fn update(&mut self) {
+        while let Ok((at, asserted)) = self.level.try_recv() {
+            if asserted && !self.asserted {
+                self.pending_ri = Some(at);
+                self.pending_ring = None;
+            }
+            self.asserted = asserted;
+        }
+    }
+
+    /// Note a line that arrived from the modem
+    fn received(&mut self, line: &[u8], at: Instant) {
+        self.update();
+        if line.iter().any(|b| !b.is_ascii_whitespace()) {
+            self.last_data = Some(at);
+        }
+        let ring = line == b"RING\r\n" || CLIP_RE.is_match(line);
+        if ring && !self.asserted && self.pending_ring.is_none() {
+            self.pending_ring = Some(at);
+        }
+    }
+
+    /// What has gone unexplained for too long
+    fn check(&mut self, now: Instant) -> Vec<Diagnostic> {
+        self.update();
+        let mut diagnostics = Vec::new();
+        if let Some(since) = self.pending_ri {
+            // Whatever the URC was, the UART is alive
+            if matches!(self.last_data, Some(last) if last >= since) {
+                self.pending_ri = None;
+            } else if now > since + RI_WINDOW {
+                self.pending_ri = None;
+                diagnostics.push(Diagnostic::RingWithoutUrc);
+            }
+        }
+        if matches!(self.pending_ring, Some(since) if now > since + RI_WINDOW) {
+            self.pending_ring = None;
+            diagnostics.push(Diagnostic::UrcWithoutRing);
+        }
+        diagnostics
+    }
+}
 
 pub struct Modem<PP: OutputPin> {
-    port: BufReader<Port>,
+    port: BufReader<Box<dyn Transport>>,
     chan: mpsc::Sender<Event>,
     pwr_gpio: PP,
     logger: Logger,
+    queue: VecDeque<Command>,
+    in_flight: Option<InFlight>,
+    state: ModemState,
+    state_since: Instant,
+    inventory: Option<Inventory>,
+    balance_check: Option<UssdCheck>,
+    pin: Option<String>,
+    ri_check: Option<RiCheck>,
+    /// Keep caller IDs out of the logs
+    hide_callers: bool,
+    /// When to next read the modem clock; only set once the network has told us the time
+    next_clock_read: Option<Instant>,
+    next_signal_read: Instant,
+    requests: mpsc::Receiver<Request>,
+    request_snd: mpsc::Sender<Request>,
+    waker: Waker,
 }
 
 impl<PP: OutputPin + 'static> Modem<PP> {
-    pub fn new<P: AsRef<Path>>(
-        path: P,
+    pub fn new(
+        transport: Box<dyn Transport>,
         chan: mpsc::Sender<Event>,
         pwr_gpio: PP,
         logger: Logger,
-    ) -> Result<Self, serial::Error> {
-        let mut port = BufReader::new(serial::SystemPort::open(path.as_ref())?);
-
-        port.get_mut().reconfigure(&|settings| {
-            settings.set_baud_rate(serial::Baud115200)?;
-            settings.set_flow_control(serial::FlowSoftware);
-            settings.set_char_size(serial::Bits8);
-            settings.set_parity(serial::ParityNone);
-            settings.set_stop_bits(serial::Stop1);
-            Ok(())
-        })?;
+    ) -> Result<Self, IoError> {
+        let (mut transport, waker) = Wakeable::new(transport)?;
+        // Wake up regularly, so that queued commands and scheduled checks get a chance to run
+        transport.set_timeout(Duration::from_millis(100))?;
+        let transport: Box<dyn Transport> = Box::new(transport);
+        let port = BufReader::new(transport);
+        let (request_snd, requests) = mpsc::channel();
 
         Ok(Modem {
             port,
             chan,
             pwr_gpio,
             logger,
+            queue: VecDeque::new(),
+            in_flight: None,
+            state: ModemState::PoweredOff,
+            state_since: Instant::now(),
+            inventory: None,
+            balance_check: None,
+            pin: None,
+            hide_callers: false,
+            ri_check: None,
+            next_clock_read: None,
+            next_signal_read: Instant::now(),
+            requests,
+            request_snd,
+            waker,
         })
     }
 
-    fn send_cmd(&mut self, cmd: &[u8]) -> Result<(), IoError> {
-        self.port.get_mut().write_all(cmd)
+    /// A channel for sending commands of our own, which are queued behind the driver's
+    pub fn requests(&self) -> mpsc::Sender<Request> {
+        self.request_snd.clone()
+    }
+
+    /// Periodically send the USSD `code` once the modem is registered, and report the replies as
+    /// `Event::Ussd`
+    pub fn check_balance(&mut self, code: &str, interval: Duration) {
+        self.balance_check = Some(UssdCheck {
+            code: code.to_owned(),
+            interval,
+            next: Instant::now(),
+        });
+    }
+
+    /// Enter `pin` if the SIM asks for one
+    pub fn unlock_with(&mut self, pin: &str) {
+        self.pin = Some(pin.to_owned());
+    }
+
+    /// Wakes the modem thread while it waits for data, so that it looks at its channels at once
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    /// Watch the RI line, given when it changes level and whether it is then asserted, and report
+    /// `Event::Diagnostic` when it disagrees with what arrives on the serial port. Use `waker` on
+    /// each change, or it is only noticed at the next read timeout.
+    pub fn watch_ring_indicator(&mut self, level: mpsc::Receiver<(Instant, bool)>) {
+        self.ri_check = Some(RiCheck::new(level));
+    }
+
+    /// Leave the numbers in `+CLIP` and `+CLCC` out of the logs and passthrough replies
+    pub fn hide_callers(&mut self) {
+        self.hide_callers = true;
+    }
+
+    /// A failed write is only logged; if the modem is gone, the next read says so
+    fn send_cmd(&mut self, cmd: &[u8]) {
+        if let Err(err) = self.port.get_mut().write_all(cmd) {
+            warn!(self.logger, "Failed to send command"; "error" => %err);
+        }
+    }
+
+    /// Queue a command to be sent once all earlier commands have completed
+    fn queue_cmd(&mut self, cmd: &[u8]) {
+        self.queue_query(cmd, Reply::Ignore);
+    }
+
+    /// Queue a command whose response is handled by `complete`
+    fn queue_query(&mut self, cmd: &[u8], reply: Reply) {
+        self.queue.push_back(Command {
+            cmd: cmd.to_owned(),
+            reply,
+        });
     }
 
     pub fn spawn(self) -> Result<thread::JoinHandle<()>, IoError>
@@ -68,63 +338,527 @@ impl<PP: OutputPin + 'static> Modem<PP> {
             })
     }
 
+    /// Drop all pending commands, e.g. because the modem restarted
+    fn abort(&mut self) {
+        let in_flight = self.in_flight.take().map(|in_flight| in_flight.reply);
+        let queued = self.queue.drain(..).map(|command| command.reply);
+        for reply in in_flight.into_iter().chain(queued) {
+            if let Reply::Passthrough(reply) = reply {
+                reply
+                    .send(Response {
+                        text: Vec::new(),
+                        result: None,
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    /// Move to `state`, running its entry actions even if it is the current one
+    fn set_state(&mut self, state: ModemState) {
+        info!(self.logger, "Modem state changed";
+              "from" => format!("{:?}", self.state),
+              "to" => format!("{:?}", state),
+              "after_ms" => self.state_since.elapsed().as_millis() as u64);
+        self.state = state;
+        self.state_since = Instant::now();
+        self.chan
+            .send(Event::ModemState(state))
+            .expect("Event processing thread is dead");
+
+        match state {
+            ModemState::PoweredOff => {
+                // Make sure that the GSM is powered down, so we can power it up in a known state
+                self.abort();
+                self.pwr_gpio.set_low().ok();
+                self.send_cmd(b"AT+CPOWD=1\n");
+            }
+            ModemState::Booting => {
+                self.pwr_gpio.set_high().ok();
+            }
+            ModemState::SimCheck => {
+                self.queue_query(b"ATQ0V1E1+CPIN?\n", Reply::Boot(None));
+            }
+            ModemState::Unlocking => match self.pin.clone() {
+                Some(pin) => {
+                    info!(self.logger, "Unlocking SIM");
+                    self.queue_query(format!("AT+CPIN={}\n", pin).as_bytes(), Reply::Unlock);
+                }
+                None => {
+                    error!(self.logger, "SIM wants a PIN, but none is configured");
+                    self.set_state(ModemState::SimLocked);
+                }
+            },
+            ModemState::Configuring => {
+                self.queue_query(b"AT+CREG=1;+CLIP=1\n", Reply::Boot(None));
+                self.queue_query(b"AT+CLTS?\n", Reply::ClockSync);
+            }
+            ModemState::Registering => {
+                // Registration may have completed before its URC was enabled
+                self.queue_query(b"AT+CREG?\n", Reply::Boot(None));
+                if self.inventory.is_none() {
+                    // Placeholder, so that registering again doesn't queue the queries again
+                    self.inventory = Some(Inventory::default());
+                    for &(cmd, field) in INVENTORY_QUERIES {
+                        self.queue_query(cmd, Reply::Inventory(field));
+                    }
+                }
+            }
+            ModemState::Ready => {}
+            ModemState::Fault | ModemState::SimLocked => self.abort(),
+        }
+    }
+
     fn run(mut self) {
-        // Start by making sure that the GSM is powered down, so we can power it up in a known state
-        self.pwr_gpio.set_low().ok();
-        self.send_cmd(b"AT+CPOWD=1\n").unwrap();
-        std::thread::sleep(Duration::from_secs(1));
-        self.pwr_gpio.set_high().ok();
+        self.set_state(ModemState::PoweredOff);
         let mut line = Vec::new();
         loop {
-            // Wait for RDY
-            line.clear();
-            self.port
-                .read_until(0x0a, &mut line)
-                .expect("Failed to read data");
-            debug!(self.logger, "Received input"; "line" => String::from_utf8_lossy(&line));
-
-            if line == b"RDY\r\n" {
-                self.pwr_gpio.set_low().ok();
-            } else if let Some(cpin) = Regex::captures(&CPIN_RE, &line) {
-                // PIN request
-                match &cpin[1] {
-                    b"SIM PIN" => {
-                        info!(self.logger, "Unlocking SIM");
-                        self.send_cmd(b"ATQ0V1E1+CREG=1;+CLIP=1;+CPIN=1111\n")
-                            .unwrap();
+            // A timeout leaves any partial line in the buffer, to be completed by the next read
+            match self.port.read_until(0x0a, &mut line) {
+                Ok(0) => return self.lost("Modem connection closed".to_owned()),
+                Ok(_) if line.ends_with(b"\n") => {
+                    self.handle_line(&line);
+                    line.clear();
+                }
+                Ok(_) => {}
+                Err(ref err)
+                    if err.kind() == IoErrorKind::TimedOut
+                        || err.kind() == IoErrorKind::WouldBlock => {}
+                Err(err) => return self.lost(format!("Failed to read data: {}", err)),
+            }
+            self.poll();
+        }
+    }
+
+    /// Give up on the modem. Nothing works without it, so the daemon exits and gets restarted,
+    /// reconnecting on the way.
+    fn lost(&self, reason: String) {
+        error!(self.logger, "Lost the modem"; "reason" => &reason);
+        self.chan.send(Event::ModemLost(reason)).ok();
+    }
+
+    /// Run scheduled checks and send the next queued command, if the modem is idle
+    fn poll(&mut self) {
+        if let Some(timeout) = state_timeout(self.state) {
+            if self.state_since.elapsed() >= timeout {
+                match self.state {
+                    ModemState::PoweredOff => self.set_state(ModemState::Booting),
+                    // Maybe we missed RDY; see whether the modem answers anyway
+                    ModemState::Booting => self.set_state(ModemState::SimCheck),
+                    ModemState::Fault => self.set_state(ModemState::PoweredOff),
+                    state => {
+                        warn!(self.logger, "Modem setup timed out"; "state" => format!("{:?}", state));
+                        self.set_state(ModemState::Fault);
                     }
-                    b"READY" => {
-                        info!(self.logger, "SIM unlocked");
+                }
+            }
+        }
+
+        while let Ok(Request { cmd, reply }) = self.requests.try_recv() {
+            self.queue_query(format!("{}\n", cmd).as_bytes(), Reply::Passthrough(reply));
+        }
+
+        if let Some(ri) = self.ri_check.as_mut() {
+            for diagnostic in ri.check(Instant::now()) {
+                self.chan
+                    .send(Event::Diagnostic(diagnostic))
+                    .expect("Event processing thread is dead");
+            }
+        }
+
+        if let Some(check) = self.balance_check.as_mut() {
+            if self.state == ModemState::Ready && check.next <= Instant::now() {
+                check.next = Instant::now() + check.interval;
+                // Replies are decoded from the GSM character set; see `ussd`
+                let cmd = format!("AT+CSCS=\"GSM\";+CUSD=1,\"{}\",15\n", check.code);
+                self.queue_cmd(cmd.as_bytes());
+            }
+        }
+
+        if self.state == ModemState::Ready && self.next_signal_read <= Instant::now() {
+            self.next_signal_read = Instant::now() + SIGNAL_INTERVAL;
+            self.queue_cmd(b"AT+CSQ\n");
+        }
+
+        if let Some(next) = self.next_clock_read {
+            if next <= Instant::now() {
+                self.next_clock_read = Some(Instant::now() + CLOCK_INTERVAL);
+                self.queue_cmd(b"AT+CCLK?\n");
+            }
+        }
+
+        if let Some(ref in_flight) = self.in_flight {
+            if in_flight.started.elapsed() < COMMAND_TIMEOUT {
+                return;
+            }
+            warn!(self.logger, "Command timed out");
+            if let Some(in_flight) = self.in_flight.take() {
+                self.complete(in_flight, None);
+            }
+        }
+        if let Some(Command { cmd, reply }) = self.queue.pop_front() {
+            debug!(self.logger, "Sending command"; "cmd" => &*String::from_utf8_lossy(&cmd));
+            self.send_cmd(&cmd);
+            self.in_flight = Some(InFlight {
+                started: Instant::now(),
+                reply,
+                response: Vec::new(),
+            });
+        }
+    }
+
+    /// Handle the response to a command. `result` is the final result code, or None on timeout.
+    fn complete(&mut self, in_flight: InFlight, result: Option<&[u8]>) {
+        let ok = result == Some(b"OK\r\n");
+        match in_flight.reply {
+            Reply::Ignore => {}
+            Reply::Boot(next) => match (ok, next) {
+                (true, Some(next)) => self.set_state(next),
+                (true, None) => {}
+                (false, _) => {
+                    warn!(self.logger, "Modem setup failed";
+                          "state" => format!("{:?}", self.state),
+                          "result" => result.map(|line| String::from_utf8_lossy(line).trim().to_owned()));
+                    self.set_state(ModemState::Fault);
+                }
+            },
+            Reply::Unlock => {
+                if !ok {
+                    // The attempt may have counted even if the modem never answered
+                    error!(self.logger, "SIM PIN rejected";
+                           "result" => result.map(|line| String::from_utf8_lossy(line).trim().to_owned()));
+                    self.pin = None;
+                    self.set_state(ModemState::SimLocked);
+                }
+            }
+            Reply::ClockSync => match in_flight.response.first().map(String::as_str) {
+                Some("+CLTS: 1") if ok => self.set_state(ModemState::Registering),
+                _ => {
+                    // Have the network set the modem clock (NITZ); saved so it applies from boot.
+                    // Only written when needed, as the NVRAM wears.
+                    self.queue_query(
+                        b"AT+CLTS=1;&W\n",
+                        Reply::Boot(Some(ModemState::Registering)),
+                    );
+                }
+            },
+            Reply::Inventory(field) => {
+                let value = in_flight.response.into_iter().next().filter(|_| ok);
+                if value.is_none() {
+                    warn!(self.logger, "Inventory query failed"; "field" => format!("{:?}", field));
+                }
+                let inventory = self.inventory.get_or_insert_with(Inventory::default);
+                match field {
+                    InventoryField::Manufacturer => inventory.manufacturer = value,
+                    InventoryField::Model => inventory.model = value,
+                    InventoryField::Firmware => {
+                        inventory.firmware =
+                            value.map(|rev| rev.trim_start_matches("Revision:").to_owned())
                     }
-                    other => {
-                        warn!(self.logger, "Unknown PIN state"; "cpin" => &*String::from_utf8_lossy(other));
+                    InventoryField::Imei => inventory.imei = value,
+                    InventoryField::Imsi => inventory.imsi = value,
+                    InventoryField::Iccid => inventory.iccid = value,
+                }
+                if field == InventoryField::Iccid {
+                    self.chan
+                        .send(Event::Inventory(inventory.clone()))
+                        .expect("Event processing thread is dead");
+                }
+            }
+            Reply::Passthrough(reply) => {
+                // Whoever asked may have given up waiting
+                reply
+                    .send(Response {
+                        text: in_flight.response,
+                        result: result.map(|line| String::from_utf8_lossy(line).trim().to_owned()),
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: &[u8]) {
+        if self.hide_callers && (line.starts_with(b"+CLIP") || line.starts_with(b"+CLCC")) {
+            debug!(self.logger, "Received caller ID");
+        } else {
+            debug!(self.logger, "Received input"; "line" => &*String::from_utf8_lossy(line));
+        }
+
+        if let Some(ri) = self.ri_check.as_mut() {
+            ri.received(line, Instant::now());
+        }
+
+        // Passthrough commands see everything the modem says, URCs included, which are still
+        // handled as usual below. Except for what a caller sends, which is nobody else's business.
+        if let Some(ref mut in_flight) = self.in_flight {
+            if in_flight.is_passthrough() && !FINAL_RE.is_match(line) && !is_from_caller(line) {
+                if self.hide_callers {
+                    in_flight.collect(&hide_numbers(line));
+                } else {
+                    in_flight.collect(line);
+                }
+            }
+        }
+
+        if line == b"RDY\r\n" && self.state == ModemState::SimLocked {
+            // Left alone, even if it restarts and asks again
+            warn!(self.logger, "Modem restarted while the SIM is locked");
+        } else if line == b"RDY\r\n" {
+            self.pwr_gpio.set_low().ok();
+            if self.state != ModemState::PoweredOff && self.state != ModemState::Booting {
+                warn!(self.logger, "Modem restarted"; "state" => format!("{:?}", self.state));
+                self.abort();
+            }
+            self.set_state(ModemState::SimCheck);
+        } else if FINAL_RE.is_match(line) {
+            match self.in_flight.take() {
+                Some(in_flight) => self.complete(in_flight, Some(line)),
+                None => {
+                    debug!(self.logger, "Unexpected result code"; "line" => &*String::from_utf8_lossy(line))
+                }
+            }
+        } else if let Some(cpin) = Regex::captures(&CPIN_RE, line) {
+            // PIN request
+            // Both the answer to `AT+CPIN?` and a URC, so repeats are expected
+            match &cpin[1] {
+                _ if self.state == ModemState::SimLocked => {}
+                b"SIM PIN" => {
+                    if self.state != ModemState::Unlocking {
+                        self.set_state(ModemState::Unlocking);
                     }
                 }
-            } else if let Some(creg) = Regex::captures(&CREG_RE, &line) {
-                let raw_data = String::from_utf8_lossy(&creg[1]);
-                let state = match i32::from_str_radix(&raw_data, 10) {
-                    Ok(0) => Regstate::Unregistered,
-                    Ok(1) => Regstate::Registered,
-                    Ok(2) => Regstate::Searching,
-                    Ok(3) => Regstate::Denied,
-                    Ok(5) => Regstate::Roaming,
-                    Ok(n) => Regstate::Unknown(n),
-                    Err(_) => {
-                        warn!(self.logger, "Unparsable regstate"; "creg" => &*raw_data);
-                        Regstate::Unknown(4)
+                b"READY" => {
+                    if self.state < ModemState::Configuring {
+                        info!(self.logger, "SIM unlocked");
+                        self.set_state(ModemState::Configuring);
                     }
-                };
+                }
+                b"SIM PUK" => {
+                    error!(self.logger, "SIM wants its PUK");
+                    self.set_state(ModemState::SimLocked);
+                }
+                other => {
+                    warn!(self.logger, "Unknown PIN state"; "cpin" => &*String::from_utf8_lossy(other));
+                    if self.state != ModemState::Fault {
+                        self.set_state(ModemState::Fault);
+                    }
+                }
+            }
+        } else if let Some(creg) = Regex::captures(&CREG_RE, line) {
+            let raw_data = String::from_utf8_lossy(&creg[1]);
+            let state = match i32::from_str_radix(&raw_data, 10) {
+                Ok(0) => Regstate::Unregistered,
+                Ok(1) => Regstate::Registered,
+                Ok(2) => Regstate::Searching,
+                Ok(3) => Regstate::Denied,
+                Ok(5) => Regstate::Roaming,
+                Ok(n) => Regstate::Unknown(n),
+                Err(_) => {
+                    warn!(self.logger, "Unparsable regstate"; "creg" => &*raw_data);
+                    Regstate::Unknown(4)
+                }
+            };
+            let registered = state == Regstate::Registered || state == Regstate::Roaming;
+            match self.state {
+                ModemState::Registering if registered => self.set_state(ModemState::Ready),
+                ModemState::Ready if !registered => self.set_state(ModemState::Registering),
+                _ => {}
+            }
 
+            self.chan
+                .send(Event::Creg(state))
+                .expect("Event processing thread is dead");
+        } else if let Some(clip) = Regex::captures(&CLIP_RE, line) {
+            self.chan
+                .send(Event::Ring(parse_clip(&clip)))
+                .expect("Event processing thread is dead");
+        } else if let Some(csq) = Regex::captures(&CSQ_RE, line) {
+            // 99 means the modem can't tell
+            let rssi = std::str::from_utf8(&csq[1])
+                .ok()
+                .and_then(|rssi| rssi.parse().ok())
+                .filter(|&rssi| rssi <= 31);
+            self.chan
+                .send(Event::Signal(rssi))
+                .expect("Event processing thread is dead");
+        } else if let Some(dtmf) = Regex::captures(&DTMF_RE, line) {
+            self.chan
+                .send(Event::Dtmf(dtmf[1][0] as char))
+                .expect("Event processing thread is dead");
+        } else if line == b"NO CARRIER\r\n" {
+            self.chan
+                .send(Event::Hangup)
+                .expect("Event processing thread is dead");
+        } else if NITZ_RE.is_match(line) {
+            // The network just told the modem what time it is
+            self.next_clock_read = Some(Instant::now());
+        } else if let Some(cclk) = Regex::captures(&CCLK_RE, line) {
+            // Until NITZ has set it, the modem clock counts from its 2004 default
+            if self.next_clock_read.is_some() {
+                match parse_cclk(&cclk) {
+                    Some(time) => self
+                        .chan
+                        .send(Event::NetworkTime(time))
+                        .expect("Event processing thread is dead"),
+                    None => {
+                        warn!(self.logger, "Invalid modem clock"; "cclk" => &*String::from_utf8_lossy(line))
+                    }
+                }
+            }
+        } else if let Some((status, reply)) = ussd::parse(line) {
+            if status == 1 {
+                // The network wants a menu selection; we have nothing more to say
+                self.queue_cmd(b"AT+CUSD=2\n");
+            } else if status > 1 {
+                warn!(self.logger, "USSD session failed"; "status" => status);
+            }
+            if let Some(reply) = reply {
                 self.chan
-                    .send(Event::Creg(state))
-                    .expect("Event processing thread is dead");
-            } else if let Some(ring) = Regex::captures(&CLIP_RE, &line) {
-                self.chan
-                    .send(Event::Ring(String::from_utf8_lossy(&ring[1]).into_owned()))
+                    .send(Event::Ussd(reply))
                     .expect("Event processing thread is dead");
-            } else {
-                debug!(self.logger, "Unrecognized data from modem"; "line" => &*String::from_utf8_lossy(&line))
             }
+        } else if let Some(ref mut in_flight) = self.in_flight {
+            if !in_flight.is_passthrough() {
+                in_flight.collect(line);
+            }
+        } else {
+            debug!(self.logger, "Unrecognized data from modem"; "line" => &*String::from_utf8_lossy(line))
         }
     }
 }
+
+/// Whether `line` is a URC about a call: who is calling, or what they keyed in. These are never
+/// the answer to a command.
+fn is_from_caller(line: &[u8]) -> bool {
+    line == b"RING\r\n" || CLIP_RE.is_match(line) || DTMF_RE.is_match(line)
+}
+
+/// Replace the numbers in the `+CLIP` and `+CLCC` lines of `text`, e.g. for `+CLCC` answers
+/// to passthrough commands
+pub fn hide_numbers(text: &[u8]) -> Cow<'_, [u8]> {
+    CALLER_RE.replace_all(text, &b"$1$2\"redacted\""[..])
+}
+
+/// Convert the fields of a `+CLIP` URC, i.e.
+/// `+CLIP: "<number>",<type>,"<subaddr>",<satype>,"<alpha>",<CLI validity>`, into a caller ID
+fn parse_clip(clip: &regex::bytes::Captures) -> CallerId {
+    let text = |i| {
+        clip.get(i)
+            .map(|m| String::from_utf8_lossy(m.as_bytes()).into_owned())
+    };
+    let number = text(1).unwrap_or_default();
+    let validity = match (
+        text(4).and_then(|code| code.parse().ok()),
+        number.is_empty(),
+    ) {
+        // Without a number, there is nothing for the network to vouch for, whatever it says
+        (Some(0), true) | (None, true) => CliValidity::Unavailable,
+        (Some(code), _) => CliValidity::from_code(code),
+        // Older modems leave the validity out, which says nothing about how the number came
+        (None, false) => CliValidity::Missing,
+    };
+    CallerId {
+        validity,
+        number,
+        number_type: text(2).and_then(|t| t.parse().ok()).unwrap_or(129),
+        alpha: text(3).filter(|alpha| !alpha.is_empty()),
+    }
+}
+
+/// Convert the fields of a `+CCLK` response into a time. The zone is given in quarter hours.
+fn parse_cclk(cclk: &regex::bytes::Captures) -> Option<DateTime<FixedOffset>> {
+    let field = |i| -> Option<i32> { std::str::from_utf8(&cclk[i]).ok()?.parse().ok() };
+    let zone = FixedOffset::east_opt(field(7)? * 15 * 60)?;
+    zone.ymd_opt(2000 + field(1)?, field(2)? as u32, field(3)? as u32)
+        .single()?
+        .and_hms_opt(field(4)? as u32, field(5)? as u32, field(6)? as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn clip(line: &str) -> CallerId {
+        parse_clip(&CLIP_RE.captures(line.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn test_hide_numbers() {
+        let text = b"+CLCC: 1,1,4,0,0,\"+32470000001\",145,\"\"\r\n\r\nOK\r\n";
+        assert_eq!(
+            &hide_numbers(text)[..],
+            &b"+CLCC: 1,1,4,0,0,\"redacted\",145,\"\"\r\n\r\nOK\r\n"[..]
+        );
+        let clip = b"\r\n+CLIP: \"32470000001\",145,\"\",,\"Alice\",0\r\n";
+        assert_eq!(
+            &hide_numbers(clip)[..],
+            &b"\r\n+CLIP: \"redacted\",145,\"\",,\"Alice\",0\r\n"[..]
+        );
+        assert_eq!(&hide_numbers(b"+CSQ: 20,0\r\n")[..], b"+CSQ: 20,0\r\n");
+    }
+
+    #[test]
+    fn test_parse_clip() {
+        let caller = clip("+CLIP: \"32470000001\",145,\"\",,\"Alice\",0\r\n");
+        assert_eq!(caller.number, "32470000001");
+        assert_eq!(caller.number_type, 145);
+        assert_eq!(caller.validity, CliValidity::Valid);
+        assert_eq!(caller.alpha.as_deref(), Some("Alice"));
+
+        let caller = clip("+CLIP: \"\",128,\"\",0,\"\",1\r\n");
+        assert_eq!(caller.number, "");
+        assert_eq!(caller.validity, CliValidity::Withheld);
+        assert_eq!(caller.alpha, None);
+
+        assert_eq!(
+            clip("+CLIP: \"0470000001\",129\r\n").validity,
+            CliValidity::Missing
+        );
+        assert_eq!(
+            clip("+CLIP: \"\",129\r\n").validity,
+            CliValidity::Unavailable
+        );
+        assert_eq!(
+            clip("+CLIP: \"32470000001\",145,\"\",0,\"\",3\r\n").validity,
+            CliValidity::Unscreened
+        );
+        assert_eq!(
+            clip("+CLIP: \"32470000001\",145,\"\",0,\"\",7\r\n").validity,
+            CliValidity::Unknown(7)
+        );
+    }
+
+    #[test]
+    fn test_ri_check() {
+        let (level_snd, level) = mpsc::channel();
+        let mut ri = RiCheck::new(level);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // RI, then the URC it announced
+        level_snd.send((at(0), true)).unwrap();
+        assert_eq!(ri.check(at(10)), vec![]);
+        ri.received(b"RING\r\n", at(100));
+        level_snd.send((at(1000), false)).unwrap();
+        assert_eq!(ri.check(at(5000)), vec![]);
+
+        // RI without anything on the serial port, reported once the window has passed
+        level_snd.send((at(6000), true)).unwrap();
+        ri.received(b"\r\n", at(6100));
+        assert_eq!(ri.check(at(7000)), vec![]);
+        assert_eq!(ri.check(at(8100)), vec![Diagnostic::RingWithoutUrc]);
+        assert_eq!(ri.check(at(9000)), vec![]);
+        level_snd.send((at(9000), false)).unwrap();
+
+        // A call without RI, unless RI catches up within the window
+        ri.received(b"RING\r\n", at(10_000));
+        level_snd.send((at(10_500), true)).unwrap();
+        assert_eq!(ri.check(at(10_500)), vec![]);
+        ri.received(b"+CLIP: \"32470000001\",145\r\n", at(10_600));
+        assert_eq!(ri.check(at(13_000)), vec![]);
+        level_snd.send((at(13_000), false)).unwrap();
+        ri.received(b"+CLIP: \"32470000001\",145\r\n", at(14_000));
+        assert_eq!(ri.check(at(15_000)), vec![]);
+        assert_eq!(ri.check(at(16_100)), vec![Diagnostic::UrcWithoutRing]);
+    }
+}
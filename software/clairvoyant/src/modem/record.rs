@@ -0,0 +1,446 @@
+//! Recording of the raw traffic between the driver and the modem, and replay of such recordings.
+//!
+//! A recording starts with `MAGIC` and the start time in milliseconds since the epoch (u64 LE).
+//! Every read or write is then stored as a record:
+//!
+//! * direction: `<` from the modem, `>` to the modem
+//! * milliseconds since the previous record (u32 LE)
+//! * length (u16 LE)
+//! * the data
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use slog::{warn, Logger};
+
+use super::transport::Transport;
+
+const MAGIC: &[u8] = b"ZUULAT1\n";
+
+/// How long replay waits for the driver to send an expected command, beyond the recorded delay
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    FromModem,
+    ToModem,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the previous record
+    pub delay: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Passes traffic through to another transport, logging it on the way
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    /// None once writing to it failed; the modem is worth more than the recording
+    log: Option<File>,
+    logger: Logger,
+    last: Instant,
+    /// What the modem said since the end of its last line, if callers' numbers are hidden. Only
+    /// whole lines are recorded then, so that a number can't be split across records.
+    partial: Option<Vec<u8>>,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(
+        inner: Box<dyn Transport>,
+        path: P,
+        logger: Logger,
+    ) -> io::Result<Self> {
+        let mut log = File::create(path)?;
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(start.as_millis() as u64).to_le_bytes());
+        log.write_all(&header)?;
+        Ok(Recorder {
+            inner,
+            log: Some(log),
+            logger,
+            last: Instant::now(),
+            partial: None,
+        })
+    }
+
+    /// Leave the numbers in `+CLIP` and `+CLCC` out of the recording
+    pub fn hide_callers(&mut self) {
+        self.partial = Some(Vec::new());
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        let log = match self.log {
+            Some(ref mut log) => log,
+            None => return,
+        };
+        let now = Instant::now();
+        let delay = now
+            .duration_since(self.last)
+            .as_millis()
+            .min(u32::MAX as u128) as u32;
+        self.last = now;
+        // Each record goes out in a single write, so a crash loses at most the one in progress
+        for chunk in data.chunks(u16::MAX as usize) {
+            let mut record = Vec::with_capacity(chunk.len() + 7);
+            record.push(match direction {
+                Direction::FromModem => b'<',
+                Direction::ToModem => b'>',
+            });
+            record.extend_from_slice(&delay.to_le_bytes());
+            record.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            record.extend_from_slice(chunk);
+            if let Err(err) = log.write_all(&record) {
+                warn!(self.logger, "Failed to record, stopping"; "error" => %err);
+                self.log = None;
+                return;
+            }
+        }
+    }
+}
+
+impl Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        match self.partial.take() {
+            Some(mut partial) => {
+                partial.extend_from_slice(&buf[..len]);
+                let end = partial
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(0, |end| end + 1);
+                let rest = partial.split_off(end);
+                if !partial.is_empty() {
+                    self.record(Direction::FromModem, &super::hide_numbers(&partial));
+                }
+                self.partial = Some(rest);
+            }
+            None => self.record(Direction::FromModem, &buf[..len]),
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.record(Direction::ToModem, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Recorder {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse a recording into its records
+pub fn load<R: Read>(mut source: R) -> io::Result<Vec<Record>> {
+    let mut header = [0u8; 16];
+    source.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not an AT recording".to_owned()));
+    }
+
+    let mut records = Vec::new();
+    let mut head = [0u8; 7];
+    loop {
+        match source.read_exact(&mut head) {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            other => other?,
+        }
+        let direction = match head[0] {
+            b'<' => Direction::FromModem,
+            b'>' => Direction::ToModem,
+            other => return Err(invalid(format!("Bad record direction {:#x}", other))),
+        };
+        let delay = u32::from_le_bytes([head[1], head[2], head[3], head[4]]);
+        let mut data = vec![0; u16::from_le_bytes([head[5], head[6]]) as usize];
+        source.read_exact(&mut data)?;
+        records.push(Record {
+            direction,
+            delay: Duration::from_millis(u64::from(delay)),
+            data,
+        });
+    }
+}
+
+/// Plays back a recording to the driver.
+///
+/// Traffic from the modem is held back until the driver has sent everything that preceded it,
+/// and commands from the driver must match the recording, so that a replay follows the same path
+/// as the recorded session. Reads fail with `InvalidData` once the driver strays from it, and
+/// return end-of-file once the recording is exhausted.
+pub struct Replay {
+    records: VecDeque<Record>,
+    /// Scales recorded delays; 0 replays as fast as possible
+    speed: f64,
+    timeout: Duration,
+    /// When the first pending record became eligible for delivery
+    since: Instant,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>, speed: f64) -> Self {
+        Replay {
+            records: records
+                .into_iter()
+                .filter(|record| !record.data.is_empty())
+                .collect(),
+            speed,
+            timeout: Duration::from_secs(1),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, speed: f64) -> io::Result<Self> {
+        Ok(Replay::new(load(File::open(path)?)?, speed))
+    }
+
+    fn scaled(&self, delay: Duration) -> Duration {
+        Duration::from_secs_f64(delay.as_secs_f64() * self.speed)
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (direction, delay) = match self.records.front() {
+            Some(record) => (record.direction, self.scaled(record.delay)),
+            None => return Ok(0),
+        };
+        let waited = self.since.elapsed();
+        if direction == Direction::ToModem {
+            if waited > delay + STALL_TIMEOUT {
+                let expected = &self.records[0].data;
+                return Err(invalid(format!(
+                    "Replay stalled waiting for {:?}",
+                    String::from_utf8_lossy(expected)
+                )));
+            }
+            thread::sleep(self.timeout.min(Duration::from_millis(10)));
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Waiting for driver",
+            ));
+        }
+        if waited < delay {
+            let remaining = delay - waited;
+            thread::sleep(remaining.min(self.timeout));
+            if remaining > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out"));
+            }
+        }
+
+        let record = &mut self.records[0];
+        let len = buf.len().min(record.data.len());
+        buf[..len].copy_from_slice(&record.data[..len]);
+        record.data.drain(..len);
+        if record.data.is_empty() {
+            self.records.pop_front();
+            self.since = Instant::now();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Commands may be sent earlier than recorded, so match against the first pending one
+        let pos = self
+            .records
+            .iter()
+            .position(|record| record.direction == Direction::ToModem)
+            .ok_or_else(|| invalid(format!("Unexpected {:?}", String::from_utf8_lossy(buf))))?;
+        let record = &mut self.records[pos];
+        let len = buf.len().min(record.data.len());
+        if buf[..len] != record.data[..len] {
+            return Err(invalid(format!(
+                "Expected {:?}, got {:?}",
+                String::from_utf8_lossy(&record.data),
+                String::from_utf8_lossy(buf)
+            )));
+        }
+        record.data.drain(..len);
+        if record.data.is_empty() {
+            self.records.remove(pos);
+            if pos == 0 {
+                self.since = Instant::now();
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::{CallerId, CliValidity, Event, Inventory, ModemState, Regstate};
+    use crate::modem::Modem;
+    use crate::pin::Pin;
+    use slog::{o, Discard};
+    use std::fs;
+    use std::sync::mpsc::channel;
+
+    fn session(records: &[(Direction, &str)]) -> Vec<Record> {
+        records
+            .iter()
+            .map(|&(direction, data)| Record {
+                direction,
+                delay: Duration::from_millis(5),
+                data: data.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let records = session(&[
+            (Direction::ToModem, "AT+CGMI\n"),
+            (Direction::FromModem, "\r\nSIMCOM_Ltd\r\n\r\nOK\r\n"),
+        ]);
+        let mut log = MAGIC.to_vec();
+        log.extend_from_slice(&0u64.to_le_bytes());
+        for record in &records {
+            log.push(match record.direction {
+                Direction::FromModem => b'<',
+                Direction::ToModem => b'>',
+            });
+            log.extend_from_slice(&5u32.to_le_bytes());
+            log.extend_from_slice(&(record.data.len() as u16).to_le_bytes());
+            log.extend_from_slice(&record.data);
+        }
+        assert_eq!(load(&log[..]).unwrap(), records);
+    }
+
+    #[test]
+    fn test_write_failure() {
+        let path = std::env::temp_dir().join(format!("zuul-record-{}", std::process::id()));
+        let replay = Replay::new(session(&[(Direction::ToModem, "AT\nAT\n")]), 0.0);
+        let logger = Logger::root(Discard, o!());
+        let mut recorder = Recorder::new(Box::new(replay), &path, logger).unwrap();
+        fs::remove_file(&path).unwrap();
+        // As if the disk had filled up
+        recorder.log = Some(
+            fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/full")
+                .unwrap(),
+        );
+        assert_eq!(recorder.write(b"AT\n").unwrap(), 3);
+        assert!(recorder.log.is_none());
+        assert_eq!(recorder.write(b"AT\n").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_replay_call() {
+        use Direction::*;
+        let replay = Replay::new(
+            session(&[
+                (ToModem, "AT+CPOWD=1\n"),
+                (
+                    FromModem,
+                    "\r\nNORMAL POWER DOWN\r\n\r\nRDY\r\n\r\n+CPIN: SIM PIN\r\n",
+                ),
+                (ToModem, "ATQ0V1E1+CPIN?\n"),
+                (FromModem, "ATQ0V1E1+CPIN?\r\n+CPIN: SIM PIN\r\n\r\nOK\r\n"),
+                (ToModem, "AT+CPIN=1111\n"),
+                (FromModem, "AT+CPIN=1111\r\n\r\nOK\r\n\r\n+CPIN: READY\r\n"),
+                (ToModem, "AT+CREG=1;+CLIP=1\n"),
+                (FromModem, "AT+CREG=1;+CLIP=1\r\n\r\nOK\r\n"),
+                (ToModem, "AT+CLTS?\n"),
+                (FromModem, "AT+CLTS?\r\n+CLTS: 0\r\n\r\nOK\r\n"),
+                (ToModem, "AT+CLTS=1;&W\n"),
+                (FromModem, "AT+CLTS=1;&W\r\n\r\nOK\r\n"),
+                (ToModem, "AT+CREG?\n"),
+                (FromModem, "AT+CREG?\r\n+CREG: 1,2\r\n\r\nOK\r\n"),
+                (ToModem, "AT+CGMI\n"),
+                (FromModem, "\r\nERROR\r\n"),
+                (ToModem, "AT+CGMM\n"),
+                (FromModem, "\r\nERROR\r\n"),
+                (ToModem, "AT+CGMR\n"),
+                (FromModem, "\r\nERROR\r\n"),
+                (ToModem, "AT+CGSN\n"),
+                (FromModem, "\r\nERROR\r\n"),
+                (ToModem, "AT+CIMI\n"),
+                (FromModem, "\r\nERROR\r\n"),
+                (ToModem, "AT+CCID\n"),
+                (FromModem, "\r\nERROR\r\n\r\n+CREG: 1\r\n"),
+                (ToModem, "AT+CSQ\n"),
+                (FromModem, "AT+CSQ\r\n+CSQ: 20,0\r\n\r\nOK\r\n"),
+                (
+                    FromModem,
+                    "\r\nRING\r\n\r\n+CLIP: \"32470000001\",145,\"\",0,\"\",0\r\n",
+                ),
+            ]),
+            0.,
+        );
+        let (chan, events) = channel();
+        let mut modem = Modem::new(
+            Box::new(replay),
+            chan,
+            Pin::Absent,
+            Logger::root(Discard, o!()),
+        )
+        .unwrap();
+        modem.unlock_with("1111");
+        modem.spawn().unwrap().join().unwrap();
+
+        let events: Vec<Event> = events.iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::ModemState(ModemState::PoweredOff),
+                Event::ModemState(ModemState::SimCheck),
+                Event::ModemState(ModemState::Unlocking),
+                Event::ModemState(ModemState::Configuring),
+                Event::ModemState(ModemState::Registering),
+                Event::Creg(Regstate::Searching),
+                Event::Inventory(Inventory::default()),
+                Event::ModemState(ModemState::Ready),
+                Event::Creg(Regstate::Registered),
+                Event::Signal(Some(20)),
+                Event::Ring(CallerId {
+                    number: "32470000001".to_owned(),
+                    number_type: 145,
+                    validity: CliValidity::Valid,
+                    alpha: None,
+                }),
+                // The recording ends
+                Event::ModemLost("Modem connection closed".to_owned()),
+            ]
+        );
+    }
+}
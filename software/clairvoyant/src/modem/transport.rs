@@ -0,0 +1,441 @@
+//! The byte streams a modem can be attached through.
+//!
+//! Reads must give up with `TimedOut` or `WouldBlock` once the timeout passes, so that the modem
+//! thread gets a chance to run its timers.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::{bail, Error};
+use serde::Deserialize;
+use serial::prelude::*;
+
+use super::record::Replay;
+
+pub trait Transport: Read + Write + Send {
+    /// Set how long a read may block waiting for data
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// What reads wait on, if it is a file descriptor, so that a `Wakeable` can also wait on it
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TtyConfig {
+    pub path: PathBuf,
+    // Default: 115200
+    baud: Option<usize>,
+    // Default: software
+    flow_control: Option<FlowControl>,
+    // Default: 8
+    data_bits: Option<u8>,
+    // Default: none
+    parity: Option<Parity>,
+    // Default: 1
+    stop_bits: Option<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum TransportConfig {
+    /// A local serial port
+    Tty(TtyConfig),
+    /// A raw TCP socket, e.g. ser2net
+    Tcp { address: String },
+    /// A pseudo-terminal for a simulator to attach to, through a symlink to the slave at `link`.
+    /// A symlink already there is replaced; anything else is left alone, and the pty not opened.
+    Pty { link: PathBuf },
+    /// Plays back a recording made with `--record`, instead of talking to a modem
+    Replay {
+        recording: PathBuf,
+        /// Scales the recorded delays; 0 replays as fast as possible. Default: 1
+        speed: Option<f64>,
+    },
+}
+
+impl TtyConfig {
+    /// The line settings the SIM800 comes up with: 115200 8N1, software flow control
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        TtyConfig {
+            path: path.as_ref().to_owned(),
+            baud: None,
+            flow_control: None,
+            data_bits: None,
+            parity: None,
+            stop_bits: None,
+        }
+    }
+
+    /// Anything else would have to be quietly replaced by the default
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(data_bits) = self.data_bits {
+            if !(5..=8).contains(&data_bits) {
+                bail!("Invalid data_bits {}; the tty takes 5 to 8", data_bits);
+            }
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            if stop_bits != 1 && stop_bits != 2 {
+                bail!("Invalid stop_bits {}; the tty takes 1 or 2", stop_bits);
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<serial::SystemPort, serial::Error> {
+        let mut port = serial::SystemPort::open(&self.path)?;
+        port.reconfigure(&|settings| {
+            settings.set_baud_rate(serial::BaudRate::from_speed(self.baud.unwrap_or(115_200)))?;
+            settings.set_flow_control(match self.flow_control.unwrap_or(FlowControl::Software) {
+                FlowControl::None => serial::FlowNone,
+                FlowControl::Software => serial::FlowSoftware,
+                FlowControl::Hardware => serial::FlowHardware,
+            });
+            settings.set_char_size(match self.data_bits.unwrap_or(8) {
+                5 => serial::Bits5,
+                6 => serial::Bits6,
+                7 => serial::Bits7,
+                _ => serial::Bits8,
+            });
+            settings.set_parity(match self.parity.unwrap_or(Parity::None) {
+                Parity::None => serial::ParityNone,
+                Parity::Odd => serial::ParityOdd,
+                Parity::Even => serial::ParityEven,
+            });
+            settings.set_stop_bits(match self.stop_bits.unwrap_or(1) {
+                2 => serial::Stop2,
+                _ => serial::Stop1,
+            });
+            Ok(())
+        })?;
+        Ok(port)
+    }
+}
+
+impl TransportConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            TransportConfig::Tty(tty) => tty.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn open(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(match self {
+            TransportConfig::Tty(tty) => Box::new(tty.open()?),
+            TransportConfig::Tcp { address } => {
+                let stream = TcpStream::connect(address.as_str())?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            TransportConfig::Pty { link } => Box::new(Pty::open(link)?),
+            TransportConfig::Replay { recording, speed } => {
+                Box::new(Replay::open(recording, speed.unwrap_or(1.))?)
+            }
+        })
+    }
+}
+
+impl Transport for serial::SystemPort {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        SerialPort::set_timeout(self, timeout)?;
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// The master side of a pseudo-terminal
+pub struct Pty {
+    master: File,
+    /// Held open so that reads don't fail with EIO while no simulator is attached
+    _slave: File,
+    timeout: Duration,
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl Pty {
+    pub fn open<P: AsRef<Path>>(link: P) -> io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let master = unsafe {
+            let fd = cvt(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            File::from_raw_fd(fd)
+        };
+        let mut name = [0 as libc::c_char; 128];
+        unsafe {
+            cvt(libc::grantpt(master.as_raw_fd()))?;
+            cvt(libc::unlockpt(master.as_raw_fd()))?;
+            cvt(libc::ptsname_r(
+                master.as_raw_fd(),
+                name.as_mut_ptr(),
+                name.len(),
+            ))?;
+        }
+        let slave_path = PathBuf::from(
+            unsafe { CStr::from_ptr(name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+        );
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&slave_path)?;
+        // No echo or line editing; the simulator wants the bytes as they were sent
+        let mut termios = termios::Termios::from_fd(slave.as_raw_fd())?;
+        termios::cfmakeraw(&mut termios);
+        termios::tcsetattr(slave.as_raw_fd(), termios::TCSANOW, &termios)?;
+
+        // Left over from an earlier run; anything else at `link` is not ours to remove
+        let link = link.as_ref();
+        match std::fs::symlink_metadata(link) {
+            Ok(meta) if meta.file_type().is_symlink() => std::fs::remove_file(link)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a symlink", link.display()),
+                ))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        symlink(&slave_path, link)?;
+
+        Ok(Pty {
+            master,
+            _slave: slave,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        Ok(cvt(unsafe { libc::poll(&mut pollfd, 1, timeout) })? > 0)
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !Pty::wait_readable(self.master.as_raw_fd(), self.timeout)? {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out"));
+        }
+        self.master.read(buf)
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl Transport for Pty {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.master.as_raw_fd())
+    }
+}
+
+/// Cuts a read on a `Wakeable` short, e.g. so that an interrupt is handled straight away
+#[derive(Clone)]
+pub struct Waker {
+    pipe: Arc<File>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        // The pipe only fills up if a wake-up is pending anyway
+        (&*self.pipe).write_all(&[0]).ok();
+    }
+}
+
+/// A transport whose reads also end, with `WouldBlock`, when its `Waker` is used. Transports
+/// without a file descriptor can't be woken, and time out as usual.
+pub struct Wakeable {
+    inner: Box<dyn Transport>,
+    wake: File,
+    timeout: Duration,
+}
+
+impl Wakeable {
+    pub fn new(inner: Box<dyn Transport>) -> io::Result<(Self, Waker)> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) })?;
+        let (wake, pipe) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let wakeable = Wakeable {
+            inner,
+            wake,
+            timeout: Duration::from_secs(1),
+        };
+        let waker = Waker {
+            pipe: Arc::new(pipe),
+        };
+        Ok((wakeable, waker))
+    }
+}
+
+impl Read for Wakeable {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = match self.inner.raw_fd() {
+            Some(fd) => fd,
+            None => return self.inner.read(buf),
+        };
+        let mut pollfds = [
+            libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wake.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = self.timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        cvt(unsafe { libc::poll(pollfds.as_mut_ptr(), 2, timeout) })?;
+        if pollfds[1].revents != 0 {
+            let mut drain = [0; 64];
+            while matches!(self.wake.read(&mut drain), Ok(len) if len > 0) {}
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "Woken up"));
+        }
+        if pollfds[0].revents == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Wakeable {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Wakeable {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        self.inner.set_timeout(timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wake() {
+        let link = std::env::temp_dir().join(format!("zuul-wake-{}", std::process::id()));
+        let pty = Pty::open(&link).unwrap();
+        let (mut transport, waker) = Wakeable::new(Box::new(pty)).unwrap();
+        transport.set_timeout(Duration::from_secs(10)).unwrap();
+
+        let started = Instant::now();
+        waker.wake();
+        waker.wake();
+        let err = transport.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Woken once, however often it was asked to
+        transport.set_timeout(Duration::from_millis(10)).unwrap();
+        let err = transport.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        std::fs::remove_file(&link).ok();
+    }
+
+    #[test]
+    fn test_pty_link() {
+        let link = std::env::temp_dir().join(format!("zuul-link-{}", std::process::id()));
+        std::fs::write(&link, "").unwrap();
+        let err = Pty::open(&link).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        std::fs::remove_file(&link).unwrap();
+        drop(Pty::open(&link).unwrap());
+        // The one left behind is replaced
+        drop(Pty::open(&link).unwrap());
+        std::fs::remove_file(&link).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let tty = |line: &str| {
+            let config = format!("transport = \"tty\"\npath = \"/dev/ttyS0\"\n{}", line);
+            toml::from_str::<TransportConfig>(&config)
+                .unwrap()
+                .validate()
+        };
+        assert!(tty("").is_ok());
+        assert!(tty("data_bits = 7\nstop_bits = 2").is_ok());
+        assert!(tty("data_bits = 9").is_err());
+        assert!(tty("stop_bits = 0").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Decoding of `+CUSD` replies.
+//!
+//! The modem is kept in the "GSM" TE character set, so GSM 7-bit replies arrive as raw GSM 03.38
+//! bytes, while UCS2 and 8-bit replies arrive hex-encoded.
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+
+lazy_static! {
+    static ref CUSD_RE: Regex =
+        Regex::new(r#"\+CUSD: *(\d+)(?:, *"([^"]*)"(?:, *(\d+))?)?"#).unwrap();
+}
+
+/// GSM 03.38 default alphabet, indexed by septet
+const GSM7_BASIC: [char; 128] = [
+    '@', '£', '$', '¥', 'è', 'é', 'ù', 'ì', 'ò', 'Ç', '\n', 'Ø', 'ø', '\r', 'Å', 'å', //
+    'Δ', '_', 'Φ', 'Γ', 'Λ', 'Ω', 'Π', 'Ψ', 'Σ', 'Θ', 'Ξ', '\x1B', 'Æ', 'æ', 'ß', 'É', //
+    ' ', '!', '"', '#', '¤', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/', //
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?', //
+    '¡', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', //
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', 'Ä', 'Ö', 'Ñ', 'Ü', '§', //
+    '¿', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', //
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', 'ä', 'ö', 'ñ', 'ü', 'à', //
+];
+
+/// GSM 03.38 extension table, for septets following an escape (0x1B)
+fn gsm7_extension(septet: u8) -> Option<char> {
+    Some(match septet {
+        0x0A => '\x0C',
+        0x14 => '^',
+        0x28 => '{',
+        0x29 => '}',
+        0x2F => '\\',
+        0x3C => '[',
+        0x3D => '~',
+        0x3E => ']',
+        0x40 => '|',
+        0x65 => '€',
+        _ => return None,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Alphabet {
+    Gsm7,
+    EightBit,
+    Ucs2,
+}
+
+/// Determine the alphabet from a CBS data coding scheme (3GPP TS 23.038, section 5)
+fn alphabet(dcs: u8) -> Alphabet {
+    match dcs >> 4 {
+        0x1 if dcs & 0x0F == 0x01 => Alphabet::Ucs2,
+        0x4..=0x7 | 0x9 => match (dcs >> 2) & 0x03 {
+            0x01 => Alphabet::EightBit,
+            0x02 => Alphabet::Ucs2,
+            _ => Alphabet::Gsm7,
+        },
+        0xF if dcs & 0x04 != 0 => Alphabet::EightBit,
+        _ => Alphabet::Gsm7,
+    }
+}
+
+fn unhex(text: &[u8]) -> Option<Vec<u8>> {
+    if text.len() % 2 == 1 {
+        return None;
+    }
+    text.chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Decode unpacked GSM 03.38 septets, one per byte
+pub fn decode_gsm7(text: &[u8]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut escaped = false;
+    for &septet in text {
+        let septet = septet & 0x7F;
+        if escaped {
+            escaped = false;
+            // Unknown extensions fall back to the basic table, as the spec requires
+            result.push(gsm7_extension(septet).unwrap_or(GSM7_BASIC[septet as usize]));
+        } else if septet == 0x1B {
+            escaped = true;
+        } else {
+            result.push(GSM7_BASIC[septet as usize]);
+        }
+    }
+    result
+}
+
+/// Decode hex-encoded big-endian UCS2
+fn decode_ucs2(text: &[u8]) -> Option<String> {
+    let raw = unhex(text)?;
+    if raw.len() % 2 == 1 {
+        return None;
+    }
+    let units = raw
+        .chunks(2)
+        .map(|pair| u16::from(pair[0]) << 8 | u16::from(pair[1]));
+    Some(
+        std::char::decode_utf16(units)
+            .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+            .collect(),
+    )
+}
+
+/// Decode the string of a `+CUSD` reply according to its data coding scheme
+pub fn decode(text: &[u8], dcs: u8) -> String {
+    match alphabet(dcs) {
+        Alphabet::Gsm7 => decode_gsm7(text),
+        Alphabet::Ucs2 => decode_ucs2(text).unwrap_or_else(|| decode_gsm7(text)),
+        Alphabet::EightBit => unhex(text)
+            .map(|raw| raw.into_iter().map(char::from).collect())
+            .unwrap_or_else(|| decode_gsm7(text)),
+    }
+}
+
+/// Parse a `+CUSD` line, returning the status and the decoded reply, if any
+pub fn parse(line: &[u8]) -> Option<(u8, Option<String>)> {
+    let cusd = CUSD_RE.captures(line)?;
+    let status = std::str::from_utf8(&cusd[1]).ok()?.parse().ok()?;
+    let dcs = cusd
+        .get(3)
+        .and_then(|dcs| std::str::from_utf8(dcs.as_bytes()).ok()?.parse().ok())
+        .unwrap_or(0x0F);
+    Some((status, cusd.get(2).map(|text| decode(text.as_bytes(), dcs))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alphabet() {
+        assert_eq!(alphabet(0x0F), Alphabet::Gsm7);
+        assert_eq!(alphabet(0x48), Alphabet::Ucs2);
+        assert_eq!(alphabet(0x44), Alphabet::EightBit);
+        assert_eq!(alphabet(0x11), Alphabet::Ucs2);
+    }
+
+    #[test]
+    fn test_gsm7() {
+        assert_eq!(decode_gsm7(b"Saldo: 5,00 \x1Be"), "Saldo: 5,00 €");
+        assert_eq!(decode_gsm7(b"\x00\x01"), "@£");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(b"+CUSD: 0,\"Uw saldo is 12,34 \x1Be\",15\r\n"),
+            Some((0, Some("Uw saldo is 12,34 €".to_string())))
+        );
+        assert_eq!(
+            parse(b"+CUSD: 0,\"0053006100630020004B20AC\",72\r\n"),
+            Some((0, Some("Sac K€".to_string())))
+        );
+        assert_eq!(parse(b"+CUSD: 4\r\n"), Some((4, None)));
+    }
+}
@@ -0,0 +1,96 @@
+//! Where events go: the MQTT broker, webhooks and local scripts, any number of each. Every
+//! backend gets the same `Report`, and can be limited to some of the event types.
+
+use crate::report::Report;
+
+pub mod exec;
+pub mod mqtt;
+pub mod webhook;
+
+pub trait Notifier {
+    /// Hand over an event, given as JSON in `document` too. This must not block for long, as
+    /// calls wait on it; failures are for the notifier to log.
+    fn notify(&self, topic: &str, document: &str, report: &Report);
+
+    /// Hand over a document that isn't an event, e.g. the status or a reply to a command. Only
+    /// backends that keep such documents for their readers need to do anything with it.
+    fn publish(&self, _topic: &str, _payload: &str, _qos: i32, _retained: bool) {}
+
+    /// Called every heartbeat, e.g. to retry what could not be delivered
+    fn heartbeat(&self) {}
+}
+
+struct Sink {
+    /// Event types to pass on; all of them if empty
+    events: Vec<String>,
+    notifier: Box<dyn Notifier>,
+}
+
+#[derive(Default)]
+pub struct Notifiers {
+    sinks: Vec<Sink>,
+}
+
+impl Notifiers {
+    pub fn add(&mut self, notifier: Box<dyn Notifier>, events: Vec<String>) {
+        self.sinks.push(Sink { events, notifier });
+    }
+
+    pub fn notify(&self, topic: &str, report: &Report) -> serde_json::Result<()> {
+        let document = report.to_json()?;
+        for sink in &self.sinks {
+            if sink.events.is_empty() || sink.events.iter().any(|event| event == report.event) {
+                sink.notifier.notify(topic, &document, report);
+            }
+        }
+        Ok(())
+    }
+
+    /// Event filters don't apply here
+    pub fn publish(&self, topic: &str, payload: &str, qos: i32, retained: bool) {
+        for sink in &self.sinks {
+            sink.notifier.publish(topic, payload, qos, retained);
+        }
+    }
+
+    pub fn heartbeat(&self) {
+        for sink in &self.sinks {
+            sink.notifier.heartbeat();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, topic: &str, _document: &str, _report: &Report) {
+            self.0.borrow_mut().push(topic.to_owned());
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let (all, opens) = Default::default();
+        let mut notifiers = Notifiers::default();
+        notifiers.add(Box::new(Recorder(Rc::clone(&all))), vec![]);
+        notifiers.add(
+            Box::new(Recorder(Rc::clone(&opens))),
+            vec!["open".to_owned()],
+        );
+
+        notifiers
+            .notify("zuul/ring", &Report::new("ring", None))
+            .unwrap();
+        notifiers
+            .notify("zuul/open", &Report::new("open", None))
+            .unwrap();
+        assert_eq!(*all.borrow(), vec!["zuul/ring", "zuul/open"]);
+        assert_eq!(*opens.borrow(), vec!["zuul/open"]);
+    }
+}
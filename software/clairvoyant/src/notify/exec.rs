@@ -0,0 +1,113 @@
+//! Events handed to a local program, in `ZUUL_*` environment variables. `ZUUL_JSON` has the whole
+//! document, and each of its fields has a variable of its own, e.g. `ZUUL_EVENT` and `ZUUL_LABEL`.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+use slog::{warn, Logger};
+
+use super::Notifier;
+use crate::report::Report;
+
+pub struct ExecNotifier {
+    program: PathBuf,
+    args: Vec<String>,
+    logger: Logger,
+}
+
+impl ExecNotifier {
+    pub fn new(program: PathBuf, args: Vec<String>, logger: Logger) -> Self {
+        ExecNotifier {
+            program,
+            args,
+            logger,
+        }
+    }
+
+    fn command(&self, topic: &str, document: &str, report: &Report) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env("ZUUL_TOPIC", topic)
+            .env("ZUUL_JSON", document)
+            .env("ZUUL_SCHEMA", report.schema.to_string())
+            .env("ZUUL_EVENT", report.event);
+        let fields = [
+            ("ZUUL_TIME", report.time.clone()),
+            ("ZUUL_NUMBER", report.number.map(str::to_owned)),
+            ("ZUUL_DECISION", report.decision.map(str::to_owned)),
+            ("ZUUL_RULE", report.rule.map(|rule| rule.to_string())),
+            ("ZUUL_LABEL", report.label.map(str::to_owned)),
+            ("ZUUL_REASON", report.reason.map(str::to_owned)),
+            (
+                "ZUUL_SOURCE",
+                report.source.map(|source| source.name().to_owned()),
+            ),
+            (
+                "ZUUL_BALANCE",
+                report.balance.map(|balance| balance.to_string()),
+            ),
+        ];
+        for (name, value) in fields.iter() {
+            if let Some(value) = value {
+                command.env(name, value);
+            }
+        }
+        command
+    }
+}
+
+impl Notifier for ExecNotifier {
+    /// The program runs in the background; a thread waits for it, so that it can't become a zombie
+    fn notify(&self, topic: &str, document: &str, report: &Report) {
+        let program = self.program.display().to_string();
+        let mut child = match self.command(topic, document, report).spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                warn!(self.logger, "Failed to run notifier"; "program" => program, "error" => %err);
+                return;
+            }
+        };
+        let logger = self.logger.clone();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                warn!(logger, "Notifier failed"; "program" => program, "status" => %status)
+            }
+            Err(err) => {
+                warn!(logger, "Failed to wait for notifier"; "program" => program, "error" => %err)
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::Source;
+
+    #[test]
+    fn test_environment() {
+        let notifier = ExecNotifier::new(
+            PathBuf::from("/bin/sh"),
+            vec![
+                "-c".to_owned(),
+                "echo $ZUUL_EVENT $ZUUL_LABEL $ZUUL_SOURCE ${ZUUL_REASON-none}".to_owned(),
+            ],
+            Logger::root(slog::Discard, slog::o!()),
+        );
+        let report = Report::new("open", None)
+            .with_label(Some("Alice"))
+            .with_source(Source::Keypad);
+        let document = report.to_json().unwrap();
+        let output = notifier
+            .command("zuul/open", &document, &report)
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "open Alice keypad none\n"
+        );
+    }
+}
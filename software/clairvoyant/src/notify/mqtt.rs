@@ -0,0 +1,101 @@
+//! Events on the broker, under the topics in the reports, e.g. `zuul/open`
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use paho_mqtt::{Client, Message};
+use slog::{info, warn, Logger};
+
+use super::Notifier;
+use crate::outbox::Outbox;
+use crate::report::Report;
+
+/// Events are acknowledged by the broker, so that one only leaves the outbox once it has it.
+/// Publishing waits for that, up to the client's timeout.
+const EVENT_QOS: i32 = 1;
+
+pub struct MqttNotifier {
+    client: Rc<Client>,
+    /// Events waiting for the broker, if they are kept at all
+    outbox: Option<RefCell<Outbox>>,
+    logger: Logger,
+}
+
+impl MqttNotifier {
+    pub fn new(client: Rc<Client>, outbox: Option<Outbox>, logger: Logger) -> Self {
+        MqttNotifier {
+            client,
+            outbox: outbox.map(RefCell::new),
+            logger,
+        }
+    }
+}
+
+impl Notifier for MqttNotifier {
+    /// Nobody may be listening at the time, so events are not retained. They are queued in the
+    /// outbox while the broker is unreachable.
+    fn notify(&self, topic: &str, document: &str, _report: &Report) {
+        let mut outbox = match self.outbox {
+            Some(ref outbox) => outbox.borrow_mut(),
+            None => {
+                self.client
+                    .publish(Message::new(topic, document, EVENT_QOS))
+                    .ok();
+                return;
+            }
+        };
+        // Anything already queued goes first, so that events arrive in order
+        if outbox.is_empty()
+            && self.client.is_connected()
+            && self
+                .client
+                .publish(Message::new(topic, document, EVENT_QOS))
+                .is_ok()
+        {
+            return;
+        }
+        match outbox.push(topic, document.to_owned()) {
+            Ok(0) => {}
+            Ok(dropped) => warn!(self.logger, "Outbox is full, dropped the oldest events";
+                                 "dropped" => dropped),
+            Err(err) => warn!(self.logger, "Failed to save outbox"; "error" => %err),
+        }
+    }
+
+    /// Not queued: the status is published again on reconnecting, and replies are of no use late
+    fn publish(&self, topic: &str, payload: &str, qos: i32, retained: bool) {
+        let message = if retained {
+            Message::new_retained(topic, payload, qos)
+        } else {
+            Message::new(topic, payload, qos)
+        };
+        self.client.publish(message).ok();
+    }
+
+    /// Publish the events queued while the broker was unreachable
+    fn heartbeat(&self) {
+        let mut outbox = match self.outbox {
+            Some(ref outbox) => outbox.borrow_mut(),
+            None => return,
+        };
+        if outbox.is_empty() || !self.client.is_connected() {
+            return;
+        }
+        let sent = outbox.flush(|queued| {
+            self.client
+                .publish(Message::new(
+                    queued.topic.as_str(),
+                    queued.payload.as_str(),
+                    EVENT_QOS,
+                ))
+                .is_ok()
+        });
+        match sent {
+            Ok(0) => {}
+            Ok(sent) => {
+                info!(self.logger, "Sent queued events"; "sent" => sent, "left" => outbox.len())
+            }
+            Err(err) => warn!(self.logger, "Failed to save outbox"; "error" => %err),
+        }
+    }
+}
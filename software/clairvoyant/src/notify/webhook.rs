@@ -0,0 +1,162 @@
+//! Events POSTed as JSON to a URL, e.g. a Node-RED flow. Only plain `http://` is spoken, so an
+//! endpoint elsewhere is best reached through a local proxy.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use slog::{warn, Logger};
+
+use super::Notifier;
+use crate::report::Report;
+
+/// Events waiting to be sent; beyond this, new ones are dropped
+const QUEUE: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Error> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return Err(format_err!("Webhooks need an http:// URL, not {:?}", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) => (&authority[..colon], authority[colon + 1..].parse()?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format_err!("Webhook URL without a host: {:?}", url));
+        }
+        Ok(Url {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// POST `body`, returning the status code of the response
+fn post(url: &Url, body: &str, timeout: Duration) -> io::Result<u16> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Host not found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: zuul/{}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.host,
+        url.port,
+        env!("CARGO_PKG_VERSION"),
+        body.len(),
+        body
+    )?;
+    // Only the status line matters, e.g. `HTTP/1.1 204 No Content`
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response"))
+}
+
+/// Sends from a thread of its own, so that a slow endpoint doesn't hold up calls
+pub struct WebhookNotifier {
+    queue: SyncSender<String>,
+    logger: Logger,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str, timeout: Duration, logger: Logger) -> Result<Self, Error> {
+        let url = Url::parse(url)?;
+        let (queue, documents) = sync_channel::<String>(QUEUE);
+        let thread_logger = logger.clone();
+        thread::Builder::new()
+            .name("webhook".to_owned())
+            .spawn(move || {
+                for document in documents {
+                    match post(&url, &document, timeout) {
+                        Ok(status) if (200..300).contains(&status) => {}
+                        Ok(status) => warn!(thread_logger, "Webhook refused event";
+                                            "host" => &url.host, "status" => status),
+                        Err(err) => warn!(thread_logger, "Webhook failed";
+                                          "host" => &url.host, "error" => %err),
+                    }
+                }
+            })?;
+        Ok(WebhookNotifier { queue, logger })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, _topic: &str, document: &str, _report: &Report) {
+        match self.queue.try_send(document.to_owned()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!(self.logger, "Webhook is behind, dropped event"),
+            Err(TrySendError::Disconnected(_)) => panic!("Webhook thread is dead"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_url() {
+        assert_eq!(
+            Url::parse("http://10.0.0.5:1880/zuul/events").unwrap(),
+            Url {
+                host: "10.0.0.5".to_owned(),
+                port: 1880,
+                path: "/zuul/events".to_owned(),
+            }
+        );
+        assert_eq!(Url::parse("http://hooks.local").unwrap().path, "/");
+        assert!(Url::parse("https://hooks.local/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_post() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        let stand_in = thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            let mut request = String::new();
+            stream.read_to_string(&mut request).ok();
+            request
+        });
+
+        let status = post(&url, r#"{"event":"open"}"#, Duration::from_secs(5)).unwrap();
+        assert_eq!(status, 204);
+        let request = stand_in.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 16\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"event\":\"open\"}"));
+    }
+}
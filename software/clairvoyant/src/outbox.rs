@@ -0,0 +1,119 @@
+//! Events that could not be published while the broker was unreachable, kept on disk until they
+//! can be. Each event carries the time it happened, so they can be sent late.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+
+use crate::state;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Queued {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// The queued events, oldest first, persisted as JSON
+pub struct Outbox {
+    path: PathBuf,
+    capacity: usize,
+    queue: VecDeque<Queued>,
+}
+
+impl Outbox {
+    /// Load the queue from `path`, which need not exist yet. An unreadable one is set aside.
+    pub fn load<P: AsRef<Path>>(path: P, capacity: usize, logger: &Logger) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let queue = state::load(&path, logger)?.unwrap_or_default();
+        let mut outbox = Outbox {
+            path,
+            capacity,
+            queue,
+        };
+        outbox.trim();
+        Ok(outbox)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        state::save(&self.path, serde_json::to_string(&self.queue)?.as_bytes())
+    }
+
+    /// Drop the oldest events beyond the capacity, returning how many
+    fn trim(&mut self) -> usize {
+        let excess = self.queue.len().saturating_sub(self.capacity);
+        self.queue.drain(..excess);
+        excess
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queue an event, making room by dropping the oldest ones. Returns how many were dropped.
+    pub fn push(&mut self, topic: &str, payload: String) -> io::Result<usize> {
+        self.queue.push_back(Queued {
+            topic: topic.to_owned(),
+            payload,
+        });
+        let dropped = self.trim();
+        self.save()?;
+        Ok(dropped)
+    }
+
+    /// Hand the events to `send` in order, until it fails, and forget the ones it took.
+    /// Returns how many it took. An event may be sent twice if we crash in between.
+    pub fn flush<F: FnMut(&Queued) -> bool>(&mut self, mut send: F) -> io::Result<usize> {
+        let mut sent = 0;
+        while let Some(queued) = self.queue.front() {
+            if !send(queued) {
+                break;
+            }
+            self.queue.pop_front();
+            sent += 1;
+        }
+        if sent > 0 {
+            self.save()?;
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Discard};
+    use std::fs;
+
+    #[test]
+    fn test_outbox() {
+        let logger = Logger::root(Discard, o!());
+        let path = std::env::temp_dir().join(format!("zuul-outbox-{}", std::process::id()));
+        let mut outbox = Outbox::load(&path, 3, &logger).unwrap();
+        for n in 0..4 {
+            let dropped = outbox.push("zuul/ring", n.to_string()).unwrap();
+            assert_eq!(dropped, if n < 3 { 0 } else { 1 });
+        }
+
+        let mut outbox = Outbox::load(&path, 2, &logger).unwrap();
+        assert_eq!(outbox.len(), 2);
+        let mut sent = Vec::new();
+        let flushed = outbox.flush(|queued| {
+            sent.push(queued.payload.clone());
+            sent.len() < 2
+        });
+        assert_eq!(flushed.unwrap(), 1);
+        assert_eq!(sent, vec!["2", "3"]);
+
+        let outbox = Outbox::load(&path, 2, &logger).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.queue[0].payload, "3");
+    }
+}
@@ -0,0 +1,226 @@
+//! Unknown callers, kept until an admin approves or dismisses them, so that letting a new member
+//! in is a matter of approving the number they just called from.
+//!
+//! Callers are referred to by their number, or by `#<id>`, e.g. `#3`, for when the numbers are
+//! hidden on MQTT.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+
+use crate::state;
+use crate::whitelist;
+
+/// Beyond this, the callers that were heard from least recently are dropped
+const MAX_PENDING: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingCaller {
+    /// Stays the same while the caller is pending. 0 in lists saved before there were ids.
+    #[serde(default)]
+    pub id: u32,
+    pub number: String,
+    /// RFC 3339, or None if the time was unknown
+    pub first_call: Option<String>,
+    pub last_call: Option<String>,
+    pub calls: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    next_id: u32,
+    callers: Vec<PendingCaller>,
+}
+
+/// Lists saved before there were ids are plain arrays
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnySaved {
+    Saved(Saved),
+    Callers(Vec<PendingCaller>),
+}
+
+/// The pending callers, persisted as JSON
+pub struct PendingList {
+    path: PathBuf,
+    /// Ids aren't reused, so that an approval can't hit someone who called since
+    next_id: u32,
+    callers: Vec<PendingCaller>,
+}
+
+impl PendingList {
+    /// Load the list from `path`, which need not exist yet. An unreadable one is set aside.
+    pub fn load<P: AsRef<Path>>(path: P, logger: &Logger) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let saved = state::load(&path, logger)?.unwrap_or(AnySaved::Callers(Vec::new()));
+        let (next_id, mut callers) = match saved {
+            AnySaved::Saved(Saved { next_id, callers }) => (next_id, callers),
+            AnySaved::Callers(callers) => (1, callers),
+        };
+        let mut next_id = callers
+            .iter()
+            .map(|caller| caller.id + 1)
+            .fold(next_id.max(1), u32::max);
+        for caller in callers.iter_mut().filter(|caller| caller.id == 0) {
+            caller.id = next_id;
+            next_id += 1;
+        }
+        Ok(PendingList {
+            path,
+            next_id,
+            callers,
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let saved = Saved {
+            next_id: self.next_id,
+            callers: self.callers.clone(),
+        };
+        state::save(&self.path, serde_json::to_string_pretty(&saved)?.as_bytes())
+    }
+
+    pub fn callers(&self) -> &[PendingCaller] {
+        &self.callers
+    }
+
+    /// Note a call from an unknown number
+    pub fn record(&mut self, number: &str, now: Option<DateTime<Local>>) -> io::Result<()> {
+        let now = now.map(|now| now.to_rfc3339());
+        match self
+            .callers
+            .iter()
+            .position(|caller| caller.number == number)
+        {
+            Some(pos) => {
+                let mut caller = self.callers.remove(pos);
+                caller.last_call = now;
+                caller.calls += 1;
+                self.callers.push(caller);
+            }
+            None => {
+                if self.callers.len() >= MAX_PENDING {
+                    self.callers.remove(0);
+                }
+                self.callers.push(PendingCaller {
+                    id: self.next_id,
+                    number: number.to_owned(),
+                    first_call: now.clone(),
+                    last_call: now,
+                    calls: 1,
+                });
+                self.next_id += 1;
+            }
+        }
+        self.save()
+    }
+
+    /// Let a pending caller in, by adding a rule for them to the whitelist at `whitelist`.
+    /// Returns who that was.
+    pub fn approve(
+        &mut self,
+        caller: &str,
+        label: &str,
+        until: Option<NaiveDate>,
+        whitelist: &Path,
+    ) -> io::Result<PendingCaller> {
+        let pos = self.position(caller)?;
+        whitelist::append_rule(whitelist, &self.callers[pos].number, label, until)?;
+        let caller = self.callers.remove(pos);
+        self.save()?;
+        Ok(caller)
+    }
+
+    pub fn dismiss(&mut self, caller: &str) -> io::Result<PendingCaller> {
+        let pos = self.position(caller)?;
+        let caller = self.callers.remove(pos);
+        self.save()?;
+        Ok(caller)
+    }
+
+    /// Find a caller by their number or `#<id>`
+    fn position(&self, caller: &str) -> io::Result<usize> {
+        let id = caller
+            .strip_prefix('#')
+            .map(|id| id.parse::<u32>().unwrap_or(0));
+        self.callers
+            .iter()
+            .position(|pending| match id {
+                Some(id) => pending.id == id,
+                None => pending.number == caller,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such pending caller"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Discard};
+    use std::fs;
+
+    #[test]
+    fn test_approve() {
+        let logger = Logger::root(Discard, o!());
+        let dir = std::env::temp_dir().join(format!("zuul-pending-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let whitelist = dir.join("whitelist");
+        fs::write(&whitelist, "").unwrap();
+
+        let mut pending = PendingList::load(dir.join("pending.json"), &logger).unwrap();
+        pending.record("32470000002", None).unwrap();
+        pending.record("32470000003", Some(Local::now())).unwrap();
+        pending.record("32470000002", Some(Local::now())).unwrap();
+        assert!(pending
+            .approve("32470000004", "Carol", None, &whitelist)
+            .is_err());
+        pending
+            .approve("32470000003", "Bob", None, &whitelist)
+            .unwrap();
+        pending.record("32470000005", None).unwrap();
+        assert_eq!(pending.callers()[1].id, 3);
+        assert!(pending.dismiss("#2").is_err());
+        assert!(pending.dismiss("#0").is_err());
+        let dismissed = pending.dismiss("#3").unwrap();
+        assert_eq!(dismissed.number, "32470000005");
+
+        let pending = PendingList::load(dir.join("pending.json"), &logger).unwrap();
+        let rules = fs::read_to_string(&whitelist).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rules, "num 32470000003 label Bob\n");
+        assert_eq!(pending.callers().len(), 1);
+        assert_eq!(pending.callers()[0].number, "32470000002");
+        assert_eq!(pending.callers()[0].id, 1);
+        assert_eq!(pending.callers()[0].calls, 2);
+        assert_eq!(pending.callers()[0].first_call, None);
+    }
+
+    #[test]
+    fn test_ids() {
+        let logger = Logger::root(Discard, o!());
+        let path = std::env::temp_dir().join(format!("zuul-pending-ids-{}", std::process::id()));
+        // As saved before there were ids
+        fs::write(
+            &path,
+            r#"[{"number": "32470000002", "first_call": null, "last_call": null, "calls": 1}]"#,
+        )
+        .unwrap();
+        let mut pending = PendingList::load(&path, &logger).unwrap();
+        assert_eq!(pending.callers()[0].id, 1);
+        pending.record("32470000003", None).unwrap();
+        pending.dismiss("#2").unwrap();
+
+        let mut pending = PendingList::load(&path, &logger).unwrap();
+        pending.record("32470000004", None).unwrap();
+        assert_eq!(pending.callers()[1].id, 3);
+
+        // A damaged list is set aside rather than keeping the daemon from starting
+        fs::write(&path, "[{").unwrap();
+        let pending = PendingList::load(&path, &logger).unwrap();
+        fs::remove_file(state::set_aside(&path)).unwrap();
+        assert!(pending.callers().is_empty());
+    }
+}
@@ -0,0 +1,26 @@
+use embedded_hal::digital::v2::OutputPin;
+
+/// An output that is either a real GPIO pin, or absent when running without GPIO (e.g. against
+/// the simulated modem)
+pub enum Pin {
+    Gpio(rppal::gpio::OutputPin),
+    Absent,
+}
+
+impl OutputPin for Pin {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), ()> {
+        if let Pin::Gpio(pin) = self {
+            pin.set_low();
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), ()> {
+        if let Pin::Gpio(pin) = self {
+            pin.set_high();
+        }
+        Ok(())
+    }
+}
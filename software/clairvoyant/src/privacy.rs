@@ -0,0 +1,130 @@
+//! How callers' numbers show up on MQTT and in the logs
+//!
+//! The whitelist and the pending list keep the numbers as they are; everything else goes through
+//! `Privacy::show`, so that a caller who is not a member doesn't end up on every dashboard.
+
+use failure::Error;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::config::PrivacyConfig;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NumberDisplay {
+    /// The number as it is
+    Clear,
+    /// Only the last few digits, e.g. `********002`
+    Mask,
+    /// A keyed hash of the number, so that calls from the same number can still be told apart
+    Pseudonym,
+    /// Nothing at all
+    Redact,
+}
+
+#[derive(Clone)]
+pub struct Privacy {
+    /// For numbers that appear in the whitelist
+    members: NumberDisplay,
+    unknown: NumberDisplay,
+    visible_digits: usize,
+    salt: Vec<u8>,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Privacy {
+            members: NumberDisplay::Clear,
+            unknown: NumberDisplay::Clear,
+            visible_digits: 0,
+            salt: Vec::new(),
+        }
+    }
+}
+
+impl Privacy {
+    pub fn new(config: &PrivacyConfig) -> Result<Self, Error> {
+        let (members, unknown) = (config.members(), config.unknown());
+        let salt = if members == NumberDisplay::Pseudonym || unknown == NumberDisplay::Pseudonym {
+            config.salt()?
+        } else {
+            Vec::new()
+        };
+        Ok(Privacy {
+            members,
+            unknown,
+            visible_digits: config.visible_digits(),
+            salt,
+        })
+    }
+
+    /// Whether any number is shown as anything but itself
+    pub fn hides_numbers(&self) -> bool {
+        self.members != NumberDisplay::Clear || self.unknown != NumberDisplay::Clear
+    }
+
+    /// The number as it may be published, for a caller who is on the whitelist or not.
+    /// A withheld number stays empty.
+    pub fn show(&self, number: &str, member: bool) -> String {
+        let display = if member { self.members } else { self.unknown };
+        if number.is_empty() {
+            return String::new();
+        }
+        match display {
+            NumberDisplay::Clear => number.to_owned(),
+            NumberDisplay::Mask => {
+                let hidden = number.chars().count().saturating_sub(self.visible_digits);
+                number
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| if i < hidden { '*' } else { c })
+                    .collect()
+            }
+            NumberDisplay::Pseudonym => {
+                let mut mac = Hmac::<Sha256>::new_varkey(&self.salt).expect("HMAC takes any key");
+                mac.input(number.as_bytes());
+                let digest = hex::encode(mac.result().code());
+                format!("anon-{}", &digest[..16])
+            }
+            NumberDisplay::Redact => "redacted".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_show() {
+        let privacy = Privacy {
+            members: NumberDisplay::Mask,
+            unknown: NumberDisplay::Pseudonym,
+            visible_digits: 3,
+            salt: b"pepper".to_vec(),
+        };
+        assert_eq!(privacy.show("32470000001", true), "********001");
+        assert_eq!(privacy.show("12", true), "12");
+        assert_eq!(privacy.show("", false), "");
+
+        let pseudonym = privacy.show("32470000002", false);
+        assert!(pseudonym.starts_with("anon-"));
+        assert_eq!(pseudonym.len(), 21);
+        assert_eq!(privacy.show("32470000002", false), pseudonym);
+        assert_ne!(privacy.show("32470000003", false), pseudonym);
+
+        let other = Privacy {
+            salt: b"salt".to_vec(),
+            ..privacy.clone()
+        };
+        assert_ne!(other.show("32470000002", false), pseudonym);
+
+        let redacting = Privacy {
+            unknown: NumberDisplay::Redact,
+            ..privacy
+        };
+        assert_eq!(redacting.show("32470000002", false), "redacted");
+        assert_eq!(Privacy::default().show("32470000002", false), "32470000002");
+    }
+}
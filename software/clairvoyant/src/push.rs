@@ -0,0 +1,192 @@
+//! Whitelists pushed over MQTT, so that several door units can be kept in step from one place.
+//! Each unit follows a retained topic of its own, `zuul/<unit>/whitelist`. Anyone on the broker
+//! can publish there, so documents are signed with an Ed25519 key of which the units only know the
+//! public half:
+//!
+//! ```json
+//! {"issued": 1570000000, "whitelist": "num 32470000001 label Alice\n", "signature": "<hex>"}
+//! ```
+//!
+//! The signature covers `<topic>\n<issued>\n<whitelist>`, so a document only applies to the unit
+//! it was signed for. Each one has to be issued after the last one applied, so that an older
+//! whitelist, e.g. one that still let someone in, can't be replayed. `issued` is only compared
+//! with earlier documents, so the unit's clock doesn't matter. If the record of the last one is
+//! lost to corruption, pushes are refused until someone has checked the whitelist and removed
+//! the record that was set aside.
+//!
+//! A pushed whitelist replaces the local file as a whole, so pending callers can't be approved on
+//! the unit meanwhile; they are added to the whitelist where it is signed.
+
+use std::convert::TryFrom;
+use std::io;
+use std::path::PathBuf;
+
+use ed25519_dalek::{PublicKey, Signature};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slog::Logger;
+
+use crate::state;
+
+#[derive(Deserialize)]
+struct Signed {
+    issued: i64,
+    whitelist: String,
+    signature: String,
+}
+
+/// Which pushed whitelist is in effect
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Applied {
+    pub issued: i64,
+    /// SHA-256 of the whitelist, in hex
+    pub hash: String,
+}
+
+#[derive(Debug)]
+pub struct Pushed {
+    pub whitelist: String,
+    pub applied: Applied,
+}
+
+pub struct WhitelistPush {
+    topic: String,
+    key: PublicKey,
+    /// Where `applied` is kept, so that nothing older is accepted after a restart either
+    state: PathBuf,
+    applied: Option<Applied>,
+}
+
+impl WhitelistPush {
+    /// An unreadable `state` is set aside, see `state::set_aside`. Nothing is accepted while it
+    /// is there, as any older whitelist would pass otherwise.
+    pub fn new(unit: &str, key: &[u8], state: PathBuf, logger: &Logger) -> Result<Self, Error> {
+        let key = PublicKey::from_bytes(key)?;
+        let applied = state::load(&state, logger)?;
+        Ok(WhitelistPush {
+            topic: format!("zuul/{}/whitelist", unit),
+            key,
+            state,
+            applied,
+        })
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Check a pushed document, returning None if it is the one already in effect. Retained
+    /// documents turn up again on every connect.
+    pub fn verify(&self, payload: &[u8]) -> Result<Option<Pushed>, String> {
+        let bad = state::set_aside(&self.state);
+        if self.applied.is_none() && bad.exists() {
+            return Err(format!(
+                "State was unreadable; remove {} once the whitelist is checked",
+                bad.display()
+            ));
+        }
+        let signed: Signed =
+            serde_json::from_slice(payload).map_err(|err| format!("Invalid payload: {}", err))?;
+        let signature = hex::decode(&signed.signature)
+            .ok()
+            .and_then(|signature| Signature::try_from(&signature[..]).ok())
+            .ok_or("Invalid signature")?;
+        let message = format!("{}\n{}\n{}", self.topic, signed.issued, signed.whitelist);
+        self.key
+            .verify_strict(message.as_bytes(), &signature)
+            .map_err(|_| "Invalid signature")?;
+
+        let applied = Applied {
+            issued: signed.issued,
+            hash: hex::encode(Sha256::digest(signed.whitelist.as_bytes())),
+        };
+        match self.applied {
+            Some(ref current) if *current == applied => return Ok(None),
+            Some(ref current) if current.issued >= applied.issued => {
+                return Err("Whitelist is older than the one in effect".to_owned())
+            }
+            _ => {}
+        }
+        Ok(Some(Pushed {
+            whitelist: signed.whitelist,
+            applied,
+        }))
+    }
+
+    /// Record that a pushed whitelist is in effect
+    pub fn applied(&mut self, applied: Applied) -> io::Result<()> {
+        state::save(&self.state, serde_json::to_string(&applied)?.as_bytes())?;
+        self.applied = Some(applied);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use slog::{o, Discard};
+
+    fn keypair() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn sign(topic: &str, issued: i64, whitelist: &str) -> Vec<u8> {
+        let message = format!("{}\n{}\n{}", topic, issued, whitelist);
+        serde_json::json!({
+            "issued": issued,
+            "whitelist": whitelist,
+            "signature": hex::encode(&keypair().sign(message.as_bytes()).to_bytes()[..]),
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_verify() {
+        let state = std::env::temp_dir().join(format!("zuul-push-{}.json", std::process::id()));
+        let logger = Logger::root(Discard, o!());
+        let key = keypair().public.to_bytes();
+        let mut push = WhitelistPush::new("front", &key, state.clone(), &logger).unwrap();
+        assert_eq!(push.topic(), "zuul/front/whitelist");
+
+        let whitelist = "num 32470000001 label Alice\n";
+        let pushed = push
+            .verify(&sign("zuul/front/whitelist", 100, whitelist))
+            .unwrap()
+            .unwrap();
+        assert_eq!(pushed.whitelist, whitelist);
+        assert_eq!(pushed.applied.hash.len(), 64);
+        assert!(push
+            .verify(&sign("zuul/back/whitelist", 100, whitelist))
+            .is_err());
+        let signed = String::from_utf8(sign("zuul/front/whitelist", 100, whitelist)).unwrap();
+        let tampered = signed.replace("Alice", "Mallory");
+        assert!(push.verify(tampered.as_bytes()).is_err());
+
+        push.applied(pushed.applied).unwrap();
+        // Retained, so it comes again
+        let again = sign("zuul/front/whitelist", 100, whitelist);
+        assert!(push.verify(&again).unwrap().is_none());
+        let older = sign("zuul/front/whitelist", 99, "num 32470000002\n");
+        assert!(push.verify(&older).is_err());
+
+        // Even after a restart
+        let push = WhitelistPush::new("front", &key, state.clone(), &logger).unwrap();
+        std::fs::remove_file(&state).unwrap();
+        assert!(push.verify(&older).is_err());
+        let newer = sign("zuul/front/whitelist", 101, "num 32470000002\n");
+        assert!(push.verify(&newer).unwrap().is_some());
+
+        // A corrupt state could let the older one back in
+        std::fs::write(&state, "{").unwrap();
+        let push = WhitelistPush::new("front", &key, state.clone(), &logger).unwrap();
+        assert!(push.verify(&older).is_err());
+        assert!(push.verify(&newer).is_err());
+        std::fs::remove_file(state::set_aside(&state)).unwrap();
+        assert!(push.verify(&newer).unwrap().is_some());
+    }
+}
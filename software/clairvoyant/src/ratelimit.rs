@@ -0,0 +1,198 @@
+//! Token buckets that calls are taken from before the whitelist is consulted, so that nobody can
+//! brute-force their way in or burn through the SIM plan by calling over and over. The global
+//! bucket is optional, and numbers on the whitelist don't take from it, so that a flood of
+//! strangers can't lock out the people who live there.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::RateLimitConfig;
+
+/// Holds up to `burst` calls, and gets one back every `refill`
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(burst: u32) -> Self {
+        Bucket {
+            tokens: f64::from(burst),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, burst: u32, refill: Duration, now: Instant) {
+        let earned = (now - self.updated).as_secs_f64() / refill.as_secs_f64().max(1e-3);
+        self.tokens = (self.tokens + earned).min(f64::from(burst));
+        self.updated = now;
+    }
+
+    fn take(&mut self, burst: u32, refill: Duration, now: Instant) -> bool {
+        self.refill(burst, refill, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Caller {
+    bucket: Bucket,
+    locked_until: Option<Instant>,
+}
+
+impl Caller {
+    fn is_locked_out(&self, now: Instant) -> bool {
+        match self.locked_until {
+            Some(until) => until > now,
+            None => false,
+        }
+    }
+}
+
+/// Why a call was turned away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// The number just used up its calls, and is locked out
+    Caller,
+    /// The number is still locked out
+    LockedOut,
+    /// All numbers together just used up their calls
+    Global,
+    /// Calls are still coming in too fast
+    Flood,
+}
+
+impl Limit {
+    /// Whether the limit was only now reached, rather than still in effect
+    pub fn tripped(self) -> bool {
+        matches!(self, Limit::Caller | Limit::Global)
+    }
+}
+
+/// Calls turned away since startup, by the limit they ran into
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RateLimitStats {
+    pub caller: u64,
+    pub global: u64,
+}
+
+pub struct RateLimiter {
+    caller_burst: u32,
+    caller_refill: Duration,
+    global_burst: u32,
+    global_refill: Duration,
+    lockout: Duration,
+    callers: HashMap<String, Caller>,
+    global: Option<Bucket>,
+    flooded: bool,
+    pub stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            caller_burst: config.caller_burst(),
+            caller_refill: config.caller_refill(),
+            global_burst: config.global_burst().unwrap_or(0),
+            global_refill: config.global_refill(),
+            lockout: config.lockout(),
+            callers: HashMap::new(),
+            global: config.global_burst().map(Bucket::full),
+            flooded: false,
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    /// Take a new call from `number` from the buckets, or say why it can't be. Numbers that are
+    /// `listed` on the whitelist only take from their own.
+    pub fn call(&mut self, number: &str, listed: bool) -> Result<(), Limit> {
+        let now = Instant::now();
+        let (burst, refill) = (self.caller_burst, self.caller_refill);
+        // Numbers that are back to a full bucket are no different from ones never seen
+        self.callers.retain(|_, caller| {
+            caller.bucket.refill(burst, refill, now);
+            caller.is_locked_out(now) || caller.bucket.tokens < f64::from(burst)
+        });
+
+        let caller = self
+            .callers
+            .entry(number.to_owned())
+            .or_insert_with(|| Caller {
+                bucket: Bucket::full(burst),
+                locked_until: None,
+            });
+        if caller.is_locked_out(now) {
+            self.stats.caller += 1;
+            return Err(Limit::LockedOut);
+        }
+        if !caller.bucket.take(burst, refill, now) {
+            caller.locked_until = Some(now + self.lockout);
+            self.stats.caller += 1;
+            return Err(Limit::Caller);
+        }
+
+        let global = match self.global {
+            Some(ref mut global) if !listed => global,
+            _ => return Ok(()),
+        };
+        if !global.take(self.global_burst, self.global_refill, now) {
+            self.stats.global += 1;
+            let limit = if self.flooded {
+                Limit::Flood
+            } else {
+                Limit::Global
+            };
+            self.flooded = true;
+            return Err(limit);
+        }
+        self.flooded = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(config: &str) -> RateLimiter {
+        RateLimiter::new(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn test_caller_limit() {
+        let mut limiter = limiter("caller_burst = 2");
+        assert_eq!(limiter.call("32470000001", true), Ok(()));
+        assert_eq!(limiter.call("32470000001", true), Ok(()));
+        assert_eq!(limiter.call("32470000001", true), Err(Limit::Caller));
+        assert_eq!(limiter.call("32470000001", true), Err(Limit::LockedOut));
+        assert_eq!(limiter.call("32470000002", false), Ok(()));
+        assert_eq!(limiter.stats.caller, 2);
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let mut limiter = limiter("global_burst = 2");
+        assert_eq!(limiter.call("32470000001", false), Ok(()));
+        assert_eq!(limiter.call("32470000002", false), Ok(()));
+        assert_eq!(limiter.call("32470000003", false), Err(Limit::Global));
+        assert_eq!(limiter.call("32470000004", false), Err(Limit::Flood));
+        // The whitelist still gets through
+        assert_eq!(limiter.call("32470000005", true), Ok(()));
+        assert_eq!(limiter.stats.global, 2);
+    }
+
+    #[test]
+    fn test_no_global_limit() {
+        let mut limiter = limiter("");
+        for n in 0..50 {
+            let number = format!("324700000{:02}", n);
+            assert_eq!(limiter.call(&number, false), Ok(()));
+        }
+    }
+}
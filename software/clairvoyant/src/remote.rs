@@ -0,0 +1,290 @@
+//! Commands received over MQTT, on `zuul/cmd/<command>`. Anyone on the broker can publish there,
+//! so each payload is signed with a secret shared with whoever may send commands:
+//!
+//! ```json
+//! {"timestamp": 1570000000, "nonce": "4f2a", "arg": "Delivery", "signature": "<hex>"}
+//! ```
+//!
+//! The signature is the HMAC-SHA256 of `<topic>\n<timestamp>\n<nonce>\n<arg>`. Commands are only
+//! accepted close to their timestamp, and each nonce only once.
+//!
+//! `zuul/cmd/approve` adds a pending caller to the whitelist, unless that is pushed, with an `arg`
+//! like that of `zuul approve`, e.g. `32470000001 Alice 2019-12-31` or `#3 Alice`, and
+//! `zuul/cmd/dismiss` forgets one, given their number or `#<id>`. The ids are on `zuul/pending`,
+//! where the numbers may be hidden.
+//!
+//! If diagnostics are enabled, `zuul/cmd/at` runs one of the read-only queries in `DIAGNOSTICS`,
+//! e.g. with `"arg": "AT+CSQ"`, for looking into a unit off-site. Its reply carries what the
+//! modem said in answer, once it has answered.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+use chrono::NaiveDate;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::modem::Response;
+
+/// Longest nonce accepted, so that remembering them stays cheap
+const MAX_NONCE_LEN: usize = 64;
+
+/// The AT queries that may be run remotely. None of them changes anything, or shows a number
+pub const DIAGNOSTICS: &[&str] = &["AT+CSQ", "AT+CREG?", "AT+COPS?", "AT+CCLK?", "AT+CPIN?"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteCommand {
+    /// Open the door, e.g. for a delivery. Carries a label for the log
+    Open(Option<String>),
+    /// Re-read the whitelist file
+    Reload,
+    /// Turn every caller away, or stop doing so
+    Lockdown(bool),
+    /// Run one of the `DIAGNOSTICS` on the modem
+    Diagnose(String),
+    /// Add a pending caller, by number or `#<id>`, to the whitelist, until the given day if any
+    Approve {
+        caller: String,
+        label: String,
+        until: Option<NaiveDate>,
+    },
+    /// Forget a pending caller, by number or `#<id>`
+    Dismiss(String),
+}
+
+/// Parse the `<caller> <label> [<until>]` of `zuul/cmd/approve`
+fn parse_approval(arg: &str) -> Result<RemoteCommand, String> {
+    let args: Vec<&str> = arg.split_whitespace().collect();
+    let (caller, label, until) = match args[..] {
+        [caller, label] => (caller, label, None),
+        [caller, label, until] => match NaiveDate::parse_from_str(until, "%Y-%m-%d") {
+            Ok(until) => (caller, label, Some(until)),
+            Err(_) => return Err("Expiry must be a date, e.g. 2019-12-31".to_owned()),
+        },
+        _ => return Err("Expected <caller> <label> [<until>]".to_owned()),
+    };
+    Ok(RemoteCommand::Approve {
+        caller: caller.to_owned(),
+        label: label.to_owned(),
+        until,
+    })
+}
+
+/// Whether `line` is part of the answer to `query`, one of the `DIAGNOSTICS`, e.g. `+CSQ: 20,0`
+/// for `AT+CSQ`. Anything else the modem said meanwhile is left out, as it may be about a call.
+pub fn answers(query: &str, line: &str) -> bool {
+    let command = query.trim_start_matches("AT").trim_end_matches('?');
+    line.starts_with(command) && line[command.len()..].starts_with(':')
+}
+
+/// A diagnostic query waiting for the modem to answer
+pub struct Diagnostic {
+    pub nonce: String,
+    pub query: String,
+    pub response: Receiver<Response>,
+}
+
+#[derive(Deserialize)]
+struct Signed {
+    timestamp: i64,
+    nonce: String,
+    #[serde(default)]
+    arg: String,
+    signature: String,
+}
+
+pub struct Verifier {
+    secret: Vec<u8>,
+    /// How far a command's timestamp may be from ours, in seconds
+    max_age: i64,
+    /// Nonces seen within `max_age`, with their timestamps
+    nonces: HashMap<String, i64>,
+    /// Whether `zuul/cmd/at` is accepted
+    diagnostics: bool,
+}
+
+impl Verifier {
+    pub fn new(secret: Vec<u8>, max_age: i64) -> Self {
+        Verifier {
+            secret,
+            max_age,
+            nonces: HashMap::new(),
+            diagnostics: false,
+        }
+    }
+
+    pub fn with_diagnostics(mut self, diagnostics: bool) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Check a command published to `topic` at `now` (in seconds since the epoch), returning the
+    /// nonce to reply with as well as the command
+    pub fn verify(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        now: i64,
+    ) -> Result<(String, RemoteCommand), String> {
+        let signed: Signed =
+            serde_json::from_slice(payload).map_err(|err| format!("Invalid payload: {}", err))?;
+        if signed.nonce.is_empty() || signed.nonce.len() > MAX_NONCE_LEN {
+            return Err("Invalid nonce".to_owned());
+        }
+
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC takes any key");
+        mac.input(
+            format!(
+                "{}\n{}\n{}\n{}",
+                topic, signed.timestamp, signed.nonce, signed.arg
+            )
+            .as_bytes(),
+        );
+        let signature = hex::decode(&signed.signature).map_err(|_| "Invalid signature")?;
+        mac.verify(&signature).map_err(|_| "Invalid signature")?;
+
+        if (now - signed.timestamp).abs() > self.max_age {
+            return Err("Command is too old".to_owned());
+        }
+        let max_age = self.max_age;
+        self.nonces
+            .retain(|_, timestamp| (now - *timestamp).abs() <= max_age);
+        if self.nonces.contains_key(&signed.nonce) {
+            return Err("Command was already received".to_owned());
+        }
+        self.nonces.insert(signed.nonce.clone(), signed.timestamp);
+
+        let command = match topic.rsplit('/').next() {
+            Some("open") if signed.arg.is_empty() => RemoteCommand::Open(None),
+            Some("open") if signed.arg.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                RemoteCommand::Open(Some(signed.arg))
+            }
+            Some("reload") => RemoteCommand::Reload,
+            Some("lockdown") if signed.arg == "on" => RemoteCommand::Lockdown(true),
+            Some("lockdown") if signed.arg == "off" => RemoteCommand::Lockdown(false),
+            Some("approve") => parse_approval(&signed.arg)?,
+            Some("dismiss") if !signed.arg.is_empty() => RemoteCommand::Dismiss(signed.arg),
+            Some("at") if self.diagnostics => {
+                let query = signed.arg.to_ascii_uppercase();
+                if !DIAGNOSTICS.contains(&query.as_str()) {
+                    return Err("Query is not allowed".to_owned());
+                }
+                RemoteCommand::Diagnose(query)
+            }
+            _ => return Err("Unknown command".to_owned()),
+        };
+        Ok((signed.nonce, command))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sign(topic: &str, timestamp: i64, nonce: &str, arg: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").unwrap();
+        mac.input(format!("{}\n{}\n{}\n{}", topic, timestamp, nonce, arg).as_bytes());
+        format!(
+            r#"{{"timestamp": {}, "nonce": "{}", "arg": "{}", "signature": "{}"}}"#,
+            timestamp,
+            nonce,
+            arg,
+            hex::encode(mac.result().code())
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut verifier = Verifier::new(b"secret".to_vec(), 60);
+        let now = 1_570_000_000;
+
+        let open = sign("zuul/cmd/open", now, "1", "Delivery");
+        assert_eq!(
+            verifier.verify("zuul/cmd/open", &open, now + 5),
+            Ok((
+                "1".to_owned(),
+                RemoteCommand::Open(Some("Delivery".to_owned()))
+            ))
+        );
+        assert!(verifier.verify("zuul/cmd/open", &open, now + 5).is_err());
+        let lockdown = sign("zuul/cmd/lockdown", now, "2", "on");
+        assert_eq!(
+            verifier
+                .verify("zuul/cmd/lockdown", &lockdown, now)
+                .unwrap()
+                .1,
+            RemoteCommand::Lockdown(true)
+        );
+
+        // Signed for another topic
+        let reload = sign("zuul/cmd/reload", now, "3", "");
+        assert!(verifier.verify("zuul/cmd/open", &reload, now).is_err());
+        assert!(verifier
+            .verify("zuul/cmd/reload", &reload, now + 61)
+            .is_err());
+        let forged = sign("zuul/cmd/open", now, "4", "Delivery");
+        let forged = String::from_utf8(forged)
+            .unwrap()
+            .replace("Delivery", "Mallory");
+        assert!(verifier
+            .verify("zuul/cmd/open", forged.as_bytes(), now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_approve() {
+        let now = 1_570_000_000;
+        let mut verifier = Verifier::new(b"secret".to_vec(), 60);
+        let approve = sign("zuul/cmd/approve", now, "1", "32470000001 Alice 2019-12-31");
+        assert_eq!(
+            verifier
+                .verify("zuul/cmd/approve", &approve, now)
+                .unwrap()
+                .1,
+            RemoteCommand::Approve {
+                caller: "32470000001".to_owned(),
+                label: "Alice".to_owned(),
+                until: NaiveDate::from_ymd_opt(2019, 12, 31),
+            }
+        );
+        let undated = sign("zuul/cmd/approve", now, "2", "32470000001 Alice tomorrow");
+        assert!(verifier.verify("zuul/cmd/approve", &undated, now).is_err());
+        let unlabeled = sign("zuul/cmd/approve", now, "3", "32470000001");
+        assert!(verifier
+            .verify("zuul/cmd/approve", &unlabeled, now)
+            .is_err());
+        let dismiss = sign("zuul/cmd/dismiss", now, "4", "32470000001");
+        assert_eq!(
+            verifier
+                .verify("zuul/cmd/dismiss", &dismiss, now)
+                .unwrap()
+                .1,
+            RemoteCommand::Dismiss("32470000001".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let now = 1_570_000_000;
+        let csq = sign("zuul/cmd/at", now, "1", "at+csq");
+        let mut verifier = Verifier::new(b"secret".to_vec(), 60);
+        assert!(verifier.verify("zuul/cmd/at", &csq, now).is_err());
+
+        let mut verifier = Verifier::new(b"secret".to_vec(), 60).with_diagnostics(true);
+        assert_eq!(
+            verifier.verify("zuul/cmd/at", &csq, now).unwrap().1,
+            RemoteCommand::Diagnose("AT+CSQ".to_owned())
+        );
+        let dial = sign("zuul/cmd/at", now, "2", "ATD+32470000001;");
+        assert!(verifier.verify("zuul/cmd/at", &dial, now).is_err());
+        let chained = sign("zuul/cmd/at", now, "3", "AT+CSQ;+CFUN=0");
+        assert!(verifier.verify("zuul/cmd/at", &chained, now).is_err());
+
+        assert!(answers("AT+CSQ", "+CSQ: 20,0"));
+        assert!(answers("AT+CREG?", "+CREG: 1,1"));
+        assert!(!answers("AT+CSQ", "+CLCC: 1,1,4,0,0,\"32470000001\",145"));
+        assert!(!answers("AT+CREG?", "+CREGX: 1"));
+    }
+}
@@ -0,0 +1,194 @@
+//! The JSON documents published on MQTT for calls, doors opening and alerts
+//!
+//! `schema` goes up whenever a field changes meaning or goes away. Fields may be added without
+//! that, so consumers should ignore the ones they don't know.
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::ratelimit::Limit;
+use crate::whitelist::Decision;
+
+pub const SCHEMA: u32 = 1;
+
+/// The event types, for picking which ones a notifier gets
+pub const EVENTS: &[&str] = &[
+    "ring",
+    "open",
+    "denied",
+    "modem-diagnostic",
+    "sim-locked",
+    "rate-limit",
+    "keypad-lockout",
+    "low-balance",
+];
+
+/// What opened the door, or asked to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Call,
+    Keypad,
+    /// A signed command on `zuul/cmd/open`
+    Remote,
+    /// The Home Assistant open button
+    HomeAssistant,
+}
+
+impl Source {
+    /// As serialized
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Call => "call",
+            Source::Keypad => "keypad",
+            Source::Remote => "remote",
+            Source::HomeAssistant => "home-assistant",
+        }
+    }
+}
+
+/// Something that happened, as published on MQTT. Fields that don't apply are left out
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub schema: u32,
+    /// RFC 3339, or None if the time is unknown
+    pub time: Option<String>,
+    /// E.g. `ring`, `open`, `denied`
+    pub event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<&'static str>,
+    /// The whitelist rule the decision came down to, counting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<&'a str>,
+    /// Why the caller was turned away, or what an alert is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+    /// What opened the door, or was refused opening it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<f64>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(event: &'static str, time: Option<DateTime<Local>>) -> Self {
+        Report {
+            schema: SCHEMA,
+            time: time.map(|time| time.to_rfc3339()),
+            event,
+            number: None,
+            decision: None,
+            rule: None,
+            label: None,
+            reason: None,
+            source: None,
+            balance: None,
+        }
+    }
+
+    /// An empty number, from a caller who withheld it, is left out
+    pub fn with_number(mut self, number: &'a str) -> Self {
+        if !number.is_empty() {
+            self.number = Some(number);
+        }
+        self
+    }
+
+    pub fn with_decision(mut self, decision: &Decision<'a>, rule: Option<usize>) -> Self {
+        self.decision = Some(decision.name());
+        self.rule = rule;
+        self.label = decision.label();
+        self
+    }
+
+    pub fn with_label(mut self, label: Option<&'a str>) -> Self {
+        self.label = label;
+        self
+    }
+
+    pub fn with_reason(mut self, reason: &'a str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_balance(mut self, balance: f64) -> Self {
+        self.balance = Some(balance);
+        self
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Why the whitelist turned a caller away
+pub fn denial_reason(decision: &Decision) -> &'static str {
+    match decision {
+        Decision::Accept(_) => "accepted",
+        Decision::OutOfHours => "out-of-hours",
+        Decision::AwaitingKnock => "awaiting-knock",
+        Decision::Deny => "no-matching-rule",
+    }
+}
+
+pub fn limit_reason(limit: Limit) -> &'static str {
+    match limit {
+        Limit::Caller => "caller-rate-limit",
+        Limit::LockedOut => "caller-locked-out",
+        Limit::Global => "global-rate-limit",
+        Limit::Flood => "flood",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_report() {
+        let time = Local.ymd(2019, 9, 2).and_hms(12, 0, 0);
+        let decision = Decision::Accept(Some("Alice"));
+        let report = Report::new("open", Some(time))
+            .with_number("32470000001")
+            .with_decision(&decision, Some(2))
+            .with_source(Source::Call);
+        let document: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(document["schema"], SCHEMA);
+        assert_eq!(document["time"], time.to_rfc3339());
+        assert_eq!(document["event"], "open");
+        assert_eq!(document["number"], "32470000001");
+        assert_eq!(document["decision"], "accept");
+        assert_eq!(document["rule"], 2);
+        assert_eq!(document["label"], "Alice");
+        assert_eq!(document["source"], "call");
+        for &source in &[
+            Source::Call,
+            Source::Keypad,
+            Source::Remote,
+            Source::HomeAssistant,
+        ] {
+            assert_eq!(serde_json::to_value(source).unwrap(), source.name());
+        }
+        assert!(document.get("reason").is_none());
+
+        let report = Report::new("denied", None)
+            .with_number("")
+            .with_decision(&Decision::Deny, None)
+            .with_reason(denial_reason(&Decision::Deny));
+        let document: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(document["time"], serde_json::Value::Null);
+        assert_eq!(document["reason"], "no-matching-rule");
+        assert!(document.get("number").is_none());
+        assert!(document.get("rule").is_none());
+    }
+}
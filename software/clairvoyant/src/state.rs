@@ -0,0 +1,71 @@
+//! Small JSON files the daemon keeps its state in across restarts.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use slog::{warn, Logger};
+
+/// Where `load` moves a state file it can't parse
+pub fn set_aside(path: &Path) -> PathBuf {
+    path.with_extension("bad")
+}
+
+/// Read the state in `path`, or None if there is none yet. A file that can't be parsed is moved
+/// aside, see `set_aside`, and taken as missing, so that it doesn't keep the daemon from starting.
+pub fn load<T: DeserializeOwned>(path: &Path, logger: &Logger) -> io::Result<Option<T>> {
+    let source = match fs::read(path) {
+        Ok(source) => source,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match serde_json::from_slice(&source) {
+        Ok(state) => Ok(Some(state)),
+        Err(err) => {
+            let bad = set_aside(path);
+            warn!(logger, "Moving unreadable state aside";
+                  "path" => %path.display(), "to" => %bad.display(), "error" => %err);
+            fs::rename(path, &bad)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Replace the file at `path` with `contents`, so that a crash or power loss leaves either the
+/// old file or the new one
+pub fn save(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // The rename itself is only durable once the directory is
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Discard};
+
+    #[test]
+    fn test_load() {
+        let logger = Logger::root(Discard, o!());
+        let path = std::env::temp_dir().join(format!("zuul-state-{}.json", std::process::id()));
+        assert_eq!(load::<Vec<u32>>(&path, &logger).unwrap(), None);
+
+        save(&path, b"[1, 2]").unwrap();
+        assert_eq!(load(&path, &logger).unwrap(), Some(vec![1, 2]));
+
+        fs::write(&path, "[1, ").unwrap();
+        assert_eq!(load::<Vec<u32>>(&path, &logger).unwrap(), None);
+        assert!(!path.exists());
+        let bad = set_aside(&path);
+        assert_eq!(fs::read_to_string(&bad).unwrap(), "[1, ");
+        fs::remove_file(&bad).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+//! The retained `zuul/status` document, from which dashboards tell how a door unit is doing.
+//!
+//! It is only published while connected, so its `online` is always true. When the connection is
+//! lost, the broker can't change the document, but sets `zuul/online` to `false` through our
+//! will; dashboards that show outages read that.
+
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::event::{ModemState, Regstate};
+use crate::whitelist::Decision;
+
+/// The outcome of the most recent call
+#[derive(Clone, Debug, Serialize)]
+pub struct LastDecision {
+    /// RFC 3339, or None if the time was unknown
+    pub time: Option<String>,
+    pub decision: &'static str,
+    pub label: Option<String>,
+}
+
+impl LastDecision {
+    pub fn new(decision: &Decision, time: Option<DateTime<Local>>) -> Self {
+        LastDecision {
+            time: time.map(|time| time.to_rfc3339()),
+            decision: decision.name(),
+            label: decision.label().map(str::to_owned),
+        }
+    }
+}
+
+pub struct Status {
+    started: Instant,
+    pub modem: Option<ModemState>,
+    pub registration: Option<Regstate>,
+    /// As reported by `AT+CSQ`
+    pub signal: Option<u8>,
+    pub whitelist_rules: usize,
+    pub last_decision: Option<LastDecision>,
+    /// Whether all callers are being turned away
+    pub lockdown: bool,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    online: bool,
+    version: &'static str,
+    /// In seconds
+    uptime: u64,
+    modem: Option<ModemState>,
+    registration: &'a Option<Regstate>,
+    signal_dbm: Option<i32>,
+    whitelist_rules: usize,
+    last_decision: &'a Option<LastDecision>,
+    lockdown: bool,
+}
+
+impl Status {
+    pub fn new(whitelist_rules: usize) -> Self {
+        Status {
+            started: Instant::now(),
+            modem: None,
+            registration: None,
+            signal: None,
+            whitelist_rules,
+            last_decision: None,
+            lockdown: false,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&Document {
+            online: true,
+            version: env!("CARGO_PKG_VERSION"),
+            uptime: self.started.elapsed().as_secs(),
+            modem: self.modem,
+            registration: &self.registration,
+            // 0 is -113 dBm or less, in steps of 2 dBm
+            signal_dbm: self.signal.map(|rssi| -113 + 2 * i32::from(rssi)),
+            whitelist_rules: self.whitelist_rules,
+            last_decision: &self.last_decision,
+            lockdown: self.lockdown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let mut status = Status::new(3);
+        status.modem = Some(ModemState::Ready);
+        status.registration = Some(Regstate::Roaming);
+        status.signal = Some(20);
+        status.last_decision = Some(LastDecision::new(&Decision::Accept(Some("Alice")), None));
+
+        let document: serde_json::Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(document["online"], true);
+        assert_eq!(document["modem"], "ready");
+        assert_eq!(document["registration"], "roaming");
+        assert_eq!(document["signal_dbm"], -73);
+        assert_eq!(document["whitelist_rules"], 3);
+        assert_eq!(document["last_decision"]["decision"], "accept");
+        assert_eq!(document["last_decision"]["label"], "Alice");
+    }
+}
@@ -1,5 +1,9 @@
-use chrono::{Datelike, Timelike};
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::clock::UnknownTimePolicy;
+use crate::state;
 
 mod parser;
 
@@ -9,6 +13,9 @@ pub enum FilterComponent {
     Time { start: u16, end: u16 }, // both in minutes since midnight. Both are inclusive
     Number(String),                // The number to be recognized
     Label(String),
+    Pin(String),                      // Entered on the keypad; see `keypad`
+    Knock { calls: u8, within: u16 }, // The number called this often in `within` seconds
+    Until(NaiveDate),                 // The last day the rule applies. Inclusive
 }
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -32,32 +39,114 @@ impl Day {
     const SUN: u8 = 0x40;
 }
 
+/// What to do with a caller
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision<'a> {
+    /// Let them in. Carries the label of the matching rule, if it has one
+    Accept(Option<&'a str>),
+    /// A rule would let them in, but not at this time
+    OutOfHours,
+    /// A rule would let them in once they hang up and call again
+    AwaitingKnock,
+    Deny,
+}
+
+impl<'a> Decision<'a> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Decision::Accept(_) => "accept",
+            Decision::OutOfHours => "out-of-hours",
+            Decision::AwaitingKnock => "awaiting-knock",
+            Decision::Deny => "deny",
+        }
+    }
+
+    pub fn label(&self) -> Option<&'a str> {
+        match self {
+            Decision::Accept(label) => *label,
+            _ => None,
+        }
+    }
+}
+
 pub struct MatchContext<'a> {
     number: &'a str,
-    day: u8,
-    time: u16,
+    /// Day bit and minutes since midnight, or None if the time is unknown
+    now: Option<(u8, u16)>,
+    today: Option<NaiveDate>,
+    unknown_time: UnknownTimePolicy,
+    /// The PIN entered on the keypad, if any
+    pin: Option<&'a str>,
+    /// When the recent calls from this number started, including this one
+    calls: &'a [Instant],
 }
 
 impl<'a> MatchContext<'a> {
-    pub fn new(number: &'a str) -> Self {
-        let now = chrono::Local::now();
-        let day = 1u8 << now.weekday().num_days_from_monday() as u8;
-        let time = (now.hour() * 60 + now.minute()) as u16;
-        MatchContext { number, day, time }
+    pub fn new(
+        number: &'a str,
+        now: Option<DateTime<Local>>,
+        unknown_time: UnknownTimePolicy,
+    ) -> Self {
+        let today = now.map(|now| now.date().naive_local());
+        let now = now.map(|now| {
+            let day = 1u8 << now.weekday().num_days_from_monday() as u8;
+            let time = (now.hour() * 60 + now.minute()) as u16;
+            (day, time)
+        });
+        MatchContext {
+            number,
+            now,
+            today,
+            unknown_time,
+            pin: None,
+            calls: &[],
+        }
+    }
+
+    pub fn with_pin(self, pin: &'a str) -> Self {
+        MatchContext {
+            pin: Some(pin),
+            ..self
+        }
+    }
+
+    pub fn with_calls(self, calls: &'a [Instant]) -> Self {
+        MatchContext { calls, ..self }
     }
 }
 
 impl FilterComponent {
     fn matches(&self, ctx: &MatchContext) -> bool {
+        let fail_open = ctx.unknown_time == UnknownTimePolicy::FailOpen;
         match self {
-            FilterComponent::Day(d) => (ctx.day & *d) != 0,
+            FilterComponent::Day(d) => ctx.now.map_or(fail_open, |(day, _)| (day & *d) != 0),
 
-            FilterComponent::Time { start, end } => ctx.time >= *start && ctx.time <= *end,
+            FilterComponent::Time { start, end } => ctx
+                .now
+                .map_or(fail_open, |(_, time)| time >= *start && time <= *end),
             FilterComponent::Number(num) => ctx.number == num,
             FilterComponent::Label(_) => true,
+            FilterComponent::Pin(pin) => ctx.pin == Some(pin.as_str()),
+            FilterComponent::Knock { calls, within } => {
+                let within = Duration::from_secs(u64::from(*within));
+                ctx.calls
+                    .iter()
+                    .filter(|call| call.elapsed() <= within)
+                    .count()
+                    >= usize::from(*calls)
+            }
+            FilterComponent::Until(date) => ctx.today.map_or(fail_open, |today| today <= *date),
         }
     }
 
+    fn is_time_based(&self) -> bool {
+        matches!(self, FilterComponent::Day(_) | FilterComponent::Time { .. })
+    }
+
+    fn is_knock(&self) -> bool {
+        matches!(self, FilterComponent::Knock { .. })
+    }
+
     fn label(&self) -> Option<&str> {
         if let FilterComponent::Label(lbl) = self {
             Some(lbl)
@@ -79,13 +168,56 @@ impl Filter {
             None
         }
     }
+
+    /// Whether the rule would match, if it weren't for the components `skip` picks out
+    fn matches_except(&self, ctx: &MatchContext, skip: fn(&FilterComponent) -> bool) -> bool {
+        self.0
+            .iter()
+            .filter(|component| !skip(component))
+            .all(|component| component.matches(ctx))
+    }
+}
+
+/// Append a rule that lets `number` in, until the end of `until` if given
+pub fn append_rule(
+    path: &Path,
+    number: &str,
+    label: &str,
+    until: Option<NaiveDate>,
+) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind, Write};
+    let valid =
+        |text: &str, allowed: fn(char) -> bool| !text.is_empty() && text.chars().all(allowed);
+    if !valid(number, |c| c.is_ascii_digit() || c == '#' || c == '*')
+        || !valid(label, |c| c.is_ascii_alphanumeric())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid number or label",
+        ));
+    }
+
+    let mut rule = format!("num {} label {}", number, label);
+    if let Some(until) = until {
+        rule += &format!(" until {}", until.format("%Y-%m-%d"));
+    }
+    let source = std::fs::read_to_string(path)?;
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    // Every rule has to end in a newline, including one someone added by hand
+    if !source.is_empty() && !source.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", rule)
 }
 
 fn parse_file(path: &Path) -> std::io::Result<Vec<Filter>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+fn parse(source: &str) -> std::io::Result<Vec<Filter>> {
     use std::io::{Error, ErrorKind};
-    let source = std::fs::read_to_string(path)?;
     let (rest, parsed) =
-        parser::config(&source).map_err(|err: nom::Err<nom::error::VerboseError<_>>| {
+        parser::config(source).map_err(|err: nom::Err<nom::error::VerboseError<_>>| {
             Error::new(ErrorKind::InvalidData, failure::err_msg("Parse error"))
         })?;
     if rest != "" {
@@ -105,20 +237,219 @@ impl Whitelist {
         Ok(Whitelist { cache, source })
     }
 
-    pub(crate) fn matches(&self, ctx: &MatchContext) -> Option<Option<&str>> {
-        let mut matched = false;
-        for filter in self.cache.iter() {
+    /// Re-read the rules from the file they came from. The current rules stay if that fails.
+    pub fn reload(&mut self) -> std::io::Result<()> {
+        self.cache = parse_file(&self.source)?;
+        Ok(())
+    }
+
+    /// Swap in new rules, e.g. pushed over MQTT. They are checked before the file is replaced,
+    /// which is durable once this returns, so that a crash can't leave half a whitelist.
+    pub fn replace(&mut self, source: &str) -> std::io::Result<()> {
+        let cache = parse(source)?;
+        state::save(&self.source, source.as_bytes())?;
+        self.cache = cache;
+        Ok(())
+    }
+
+    /// The file the whitelist is read from
+    pub fn path(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Whether a rule names `number`, whether or not it would let them in now
+    pub fn lists(&self, number: &str) -> bool {
+        self.cache
+            .iter()
+            .flat_map(|filter| filter.0.iter())
+            .any(|component| *component == FilterComponent::Number(number.to_owned()))
+    }
+
+    /// How far back calls can count towards a knock
+    pub fn knock_window(&self) -> Duration {
+        let within = self
+            .cache
+            .iter()
+            .flat_map(|filter| filter.0.iter())
+            .filter_map(|component| match component {
+                FilterComponent::Knock { within, .. } => Some(*within),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        Duration::from_secs(u64::from(within))
+    }
+
+    /// Labelled rules take precedence over unlabelled ones. Also returns the rule it came down
+    /// to, by its position in the whitelist counting from 1
+    pub(crate) fn decide(&self, ctx: &MatchContext) -> (Decision<'_>, Option<usize>) {
+        let mut matched = None;
+        let mut matched_anytime = None;
+        let mut matched_unknocked = None;
+        for (index, filter) in self.cache.iter().enumerate() {
+            let rule = Some(index + 1);
             if let Some(label) = filter.matches(ctx) {
-                matched = true;
                 if label.is_some() {
-                    return Some(label);
+                    return (Decision::Accept(label), rule);
                 }
+                matched = matched.or(rule);
+            } else if filter.matches_except(ctx, FilterComponent::is_knock) {
+                matched_unknocked = matched_unknocked.or(rule);
+            } else if filter.matches_except(ctx, |c| c.is_time_based() || c.is_knock()) {
+                matched_anytime = matched_anytime.or(rule);
             }
         }
-        if matched {
-            return Some(None);
+        if matched.is_some() {
+            (Decision::Accept(None), matched)
+        } else if matched_unknocked.is_some() {
+            (Decision::AwaitingKnock, matched_unknocked)
+        } else if matched_anytime.is_some() {
+            (Decision::OutOfHours, matched_anytime)
         } else {
-            return None;
+            (Decision::Deny, None)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_decide() {
+        let whitelist = Whitelist {
+            cache: vec![
+                Filter(vec![
+                    FilterComponent::Number("32470000001".to_string()),
+                    FilterComponent::Day(Day::MON),
+                ]),
+                Filter(vec![
+                    FilterComponent::Pin("4711".to_string()),
+                    FilterComponent::Label("Guest".to_string()),
+                ]),
+            ],
+            source: PathBuf::new(),
+        };
+        let monday = Local.ymd(2019, 9, 2).and_hms(12, 0, 0);
+        let tuesday = Local.ymd(2019, 9, 3).and_hms(12, 0, 0);
+        let policy = UnknownTimePolicy::FailClosed;
+
+        let ctx = MatchContext::new("32470000001", Some(monday), policy);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::Accept(None));
+        let ctx = MatchContext::new("32470000001", Some(tuesday), policy);
+        assert_eq!(whitelist.decide(&ctx), (Decision::OutOfHours, Some(1)));
+        let ctx = MatchContext::new("32470000002", Some(monday), policy);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Deny, None));
+        let ctx = MatchContext::new("32470000002", Some(monday), policy).with_pin("4711");
+        assert_eq!(
+            whitelist.decide(&ctx),
+            (Decision::Accept(Some("Guest")), Some(2))
+        );
+    }
+
+    #[test]
+    fn test_unknown_time() {
+        let whitelist = Whitelist {
+            cache: vec![
+                Filter(vec![
+                    FilterComponent::Number("32470000001".to_string()),
+                    FilterComponent::Day(Day::MON),
+                    FilterComponent::Time {
+                        start: 8 * 60,
+                        end: 18 * 60,
+                    },
+                ]),
+                Filter(vec![
+                    FilterComponent::Number("32470000002".to_string()),
+                    FilterComponent::Until(NaiveDate::from_ymd(2019, 12, 31)),
+                ]),
+                Filter(vec![FilterComponent::Number("32470000003".to_string())]),
+            ],
+            source: PathBuf::new(),
+        };
+
+        let closed = UnknownTimePolicy::FailClosed;
+        let ctx = MatchContext::new("32470000001", None, closed);
+        assert_eq!(whitelist.decide(&ctx), (Decision::OutOfHours, Some(1)));
+        // It may have expired
+        let ctx = MatchContext::new("32470000002", None, closed);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Deny, None));
+        let ctx = MatchContext::new("32470000003", None, closed);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Accept(None), Some(3)));
+
+        let open = UnknownTimePolicy::FailOpen;
+        let ctx = MatchContext::new("32470000001", None, open);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Accept(None), Some(1)));
+        let ctx = MatchContext::new("32470000002", None, open);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Accept(None), Some(2)));
+        let ctx = MatchContext::new("32470000004", None, open);
+        assert_eq!(whitelist.decide(&ctx), (Decision::Deny, None));
+    }
+
+    #[test]
+    fn test_append_rule() {
+        let path = std::env::temp_dir().join(format!("zuul-whitelist-{}", std::process::id()));
+        std::fs::write(&path, "num 32470000001 label Alice").unwrap();
+        let until = NaiveDate::from_ymd_opt(2019, 9, 2);
+        append_rule(&path, "32470000002", "Bob", until).unwrap();
+        assert!(append_rule(&path, "32470000003", "Bob\nnum", None).is_err());
+
+        let mut whitelist = Whitelist::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(whitelist.rule_count(), 2);
+        let policy = UnknownTimePolicy::FailClosed;
+        let monday = Local.ymd(2019, 9, 2).and_hms(12, 0, 0);
+        let tuesday = Local.ymd(2019, 9, 3).and_hms(12, 0, 0);
+        let ctx = MatchContext::new("32470000002", Some(monday), policy);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::Accept(Some("Bob")));
+        let ctx = MatchContext::new("32470000002", Some(tuesday), policy);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::Deny);
+        assert!(whitelist.reload().is_err());
+        assert_eq!(whitelist.rule_count(), 2);
+    }
+
+    #[test]
+    fn test_replace() {
+        let path = std::env::temp_dir().join(format!("zuul-replace-{}", std::process::id()));
+        std::fs::write(&path, "num 32470000001 label Alice\n").unwrap();
+        let mut whitelist = Whitelist::new(&path).unwrap();
+
+        assert!(whitelist.replace("num 32470000002 frobnicate\n").is_err());
+        assert_eq!(whitelist.rule_count(), 1);
+        let pushed = "num 32470000001 label Alice\nnum 32470000002 label Bob\n";
+        whitelist.replace(pushed).unwrap();
+        assert_eq!(whitelist.rule_count(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pushed);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_knock() {
+        let whitelist = Whitelist {
+            cache: vec![Filter(vec![
+                FilterComponent::Number("32470000001".to_string()),
+                FilterComponent::Knock {
+                    calls: 2,
+                    within: 20,
+                },
+            ])],
+            source: PathBuf::new(),
+        };
+        assert_eq!(whitelist.knock_window(), Duration::from_secs(20));
+        let policy = UnknownTimePolicy::FailClosed;
+        let now = Instant::now();
+        let calls = [now];
+        let ctx = MatchContext::new("32470000001", None, policy).with_calls(&calls);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::AwaitingKnock);
+        let calls = [now, now];
+        let ctx = MatchContext::new("32470000001", None, policy).with_calls(&calls);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::Accept(None));
+        let ctx = MatchContext::new("32470000002", None, policy).with_calls(&calls);
+        assert_eq!(whitelist.decide(&ctx).0, Decision::Deny);
+    }
+}
@@ -7,6 +7,7 @@ use nom::{
 };
 
 use super::{Day, Filter, FilterComponent};
+use chrono::NaiveDate;
 use nom::character::complete::{char, one_of, space0};
 use nom::error::ErrorKind;
 
@@ -20,7 +21,15 @@ fn rule<'a, Err: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Filter, Er
 fn filter_component<'a, Err: ParseError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, FilterComponent, Err> {
-    alt((day_filter, time_filter, number_filter, label_filter))(i)
+    alt((
+        day_filter,
+        time_filter,
+        number_filter,
+        label_filter,
+        pin_filter,
+        knock_filter,
+        until_filter,
+    ))(i)
 }
 
 fn day_filter<'a, Err: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FilterComponent, Err> {
@@ -143,6 +152,46 @@ fn label_filter<'a, Err: ParseError<&'a str>>(
     Ok((i, FilterComponent::Label(label.to_string())))
 }
 
+fn pin_filter<'a, Err: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FilterComponent, Err> {
+    let (i, _) = tag("pin")(i)?;
+    let (i, _) = space1(i)?;
+    let (i, pin) = is_a("0123456789")(i)?;
+    Ok((i, FilterComponent::Pin(pin.to_owned())))
+}
+
+/// `knock 2/20`: the number must call twice within 20 seconds
+fn knock_filter<'a, Err: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, FilterComponent, Err> {
+    let (i, _) = tag("knock")(i)?;
+    let (i, _) = space1(i)?;
+    let (i, (calls, within)) = separated_pair(ndigit(1, 1), char('/'), ndigit(1, 4))(i)?;
+    Ok((
+        i,
+        FilterComponent::Knock {
+            calls: calls as u8,
+            within,
+        },
+    ))
+}
+
+/// `until 2019-12-31`: the rule applies up to and including that day
+fn until_filter<'a, Err: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, FilterComponent, Err> {
+    let (i, _) = tag("until")(i)?;
+    let (i, _) = space1(i)?;
+    let (i, date) = map_opt(
+        tuple((
+            ndigit(4, 4),
+            preceded(char('-'), ndigit(2, 2)),
+            preceded(char('-'), ndigit(2, 2)),
+        )),
+        |(y, m, d)| NaiveDate::from_ymd_opt(i32::from(y), u32::from(m), u32::from(d)),
+    )(i)?;
+    Ok((i, FilterComponent::Until(date)))
+}
+
 pub fn comment<'a, Err: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, (), Err> {
     value(
         (),
@@ -202,6 +251,9 @@ mod test {
             # preceding comment
             day thu time 18:00-24:00
             num 12128675309 label Jenny # End-of-line comment
+            pin 4711 label Guest
+            num 12128675309 knock 2/20
+            num 12128675309 until 2019-12-31
             
             # line comment\n"
             ),
@@ -218,6 +270,21 @@ mod test {
                     Filter(vec![
                         FilterComponent::Number("12128675309".to_string()),
                         FilterComponent::Label("Jenny".to_string()),
+                    ]),
+                    Filter(vec![
+                        FilterComponent::Pin("4711".to_string()),
+                        FilterComponent::Label("Guest".to_string()),
+                    ]),
+                    Filter(vec![
+                        FilterComponent::Number("12128675309".to_string()),
+                        FilterComponent::Knock {
+                            calls: 2,
+                            within: 20
+                        },
+                    ]),
+                    Filter(vec![
+                        FilterComponent::Number("12128675309".to_string()),
+                        FilterComponent::Until(NaiveDate::from_ymd(2019, 12, 31)),
                     ])
                 ]
             ))
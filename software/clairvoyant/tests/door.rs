@@ -0,0 +1,499 @@
+//! Drives the daemon against the simulated SIM800, from power-on to an opened door. These need
+//! the simulator: `cargo test --features simulator`.
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WHITELIST: &str =
+    "num 32470000001 label Alice\npin 4711 label Guest\nnum 32470000002 knock 2/20 label Bob\n";
+
+/// A daemon running without GPIO, with its modem on a PTY
+struct Unit {
+    dir: PathBuf,
+    daemon: Child,
+    log: Receiver<String>,
+}
+
+impl Unit {
+    fn start(name: &str) -> Unit {
+        Unit::with_config(name, "[keypad]\n")
+    }
+
+    fn with_config(name: &str, config: &str) -> Unit {
+        let dir = std::env::temp_dir().join(format!("zuul-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("whitelist"), WHITELIST).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            format!(
+                "{}[sim]\npin = \"1111\"\n[sockets]\ncontrol = {:?}\n[modem]\ntransport = \"pty\"\nlink = {:?}\n",
+                config.replace("$DIR", dir.to_str().unwrap()),
+                dir.join("control"),
+                dir.join("modem")
+            ),
+        )
+        .unwrap();
+
+        let mut daemon = Command::new(env!("CARGO_BIN_EXE_clairvoyant"))
+            .arg("--no-gpio")
+            .arg("-w")
+            .arg(dir.join("whitelist"))
+            .arg("-c")
+            .arg(dir.join("config.toml"))
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let (log_snd, log) = channel();
+        let stderr = daemon.stderr.take().unwrap();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                if log_snd.send(line.unwrap_or_default()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !dir.join("modem").exists() || !dir.join("control").exists() {
+            assert!(Instant::now() < deadline, "Daemon never came up");
+            thread::sleep(Duration::from_millis(50));
+        }
+        Unit { dir, daemon, log }
+    }
+
+    /// Play a script on the simulated modem
+    fn simulate(&self, script: &str) -> Child {
+        let path = self.dir.join("script");
+        fs::write(&path, script).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_sim800"))
+            .arg(self.dir.join("modem"))
+            .arg(path)
+            .spawn()
+            .unwrap()
+    }
+
+    fn wait_for_log(&self, text: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match self.log.recv_timeout(remaining) {
+                Ok(line) if line.contains(text) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Wait for `name`, in the unit's directory, to hold what `done` looks for, returning what it
+    /// held last
+    fn wait_for_file(&self, name: &str, timeout: Duration, done: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let contents = fs::read_to_string(self.dir.join(name)).unwrap_or_default();
+            if done(&contents) || Instant::now() > deadline {
+                return contents;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Run `zuul` against the daemon, returning what it printed
+    fn zuul(&self, args: &[&str]) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_zuul"))
+            .arg("-S")
+            .arg(self.dir.join("control"))
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for Unit {
+    fn drop(&mut self) {
+        self.daemon.kill().ok();
+        self.daemon.wait().ok();
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[test]
+fn whitelisted_caller_opens_door() {
+    let unit = Unit::start("open");
+    let mut sim = unit.simulate(
+        "
+        expect AT+CPOWD=1
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn survives_line_noise_and_reset() {
+    let unit = Unit::start("noise");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        garbage 200
+        send
+        reset
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn rejected_pin_is_not_retried() {
+    let unit = Unit::start("simlocked");
+    let mut sim = unit.simulate(
+        "
+        pin 2222
+        expect +CPIN=1111
+        sleep 1
+        reset
+        sleep 3
+        ",
+    );
+    assert!(unit.wait_for_log("SIM PIN rejected", Duration::from_secs(10)));
+    assert!(sim.wait().unwrap().success());
+    assert!(!unit.wait_for_log("Unlocking SIM", Duration::from_secs(1)));
+}
+
+#[test]
+fn unknown_caller_is_not_let_in() {
+    let unit = Unit::start("unknown");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32499999999
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(!unit.wait_for_log("Opening door", Duration::from_secs(3)));
+}
+
+#[test]
+fn unverified_caller_ids_are_not_trusted() {
+    let unit = Unit::start("unscreened");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001 3
+        withheld
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("not screened by the network", Duration::from_secs(10)));
+    assert!(unit.wait_for_log("withheld by the caller", Duration::from_secs(10)));
+    assert!(!unit.wait_for_log("Opening door", Duration::from_secs(3)));
+
+    let unit = Unit::with_config(
+        "unscreened-allowed",
+        "[caller_id]\nallow_unscreened = true\n",
+    );
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001 3
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn knock_opens_door_on_second_call() {
+    let unit = Unit::start("knock");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000002
+        sleep 1
+        hangup
+        ring 32470000002
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Waiting for the caller to knock", Duration::from_secs(10)));
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn repeat_callers_are_rate_limited() {
+    let unit = Unit::with_config("ratelimit", "[rate_limit]\ncaller_burst = 1\n");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        hangup
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+    assert!(unit.wait_for_log("Call rate limited", Duration::from_secs(10)));
+    assert!(!unit.wait_for_log("Opening door", Duration::from_secs(2)));
+}
+
+#[test]
+fn unknown_caller_can_be_approved() {
+    let unit = Unit::with_config("pending", "[pending]\npath = \"$DIR/pending.json\"\n");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32499999999
+        hangup
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    let pending = unit.wait_for_file("pending.json", Duration::from_secs(10), |pending| {
+        pending.contains("32499999999")
+    });
+    assert!(pending.contains("32499999999"), "{}", pending);
+    let pending = unit.zuul(&["pending"]);
+    assert!(
+        pending.starts_with("32499999999 id=1 calls=1 "),
+        "{}",
+        pending
+    );
+    assert_eq!(unit.zuul(&["approve", "#1", "Dave"]), "OK\n");
+    assert!(unit.wait_for_log("Whitelist reloaded", Duration::from_secs(10)));
+
+    // Calling again, now that the rule is in
+    let mut sim = unit.simulate("ring 32499999999\n");
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+    let whitelist = fs::read_to_string(unit.dir.join("whitelist")).unwrap();
+    assert!(whitelist.ends_with("num 32499999999 label Dave\n"));
+}
+
+#[test]
+fn at_passthrough() {
+    let unit = Unit::start("at");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        expect AT+CSQ
+        sleep 1
+        ",
+    );
+    assert!(unit.wait_for_log("SIM unlocked", Duration::from_secs(10)));
+    let output = unit.zuul(&["at", "AT+CSQ", "ATZ"]);
+    assert!(sim.wait().unwrap().success());
+
+    assert!(output.starts_with("+CSQ: "), "{}", output);
+    assert!(
+        output.contains("\nOK\nERROR: ATZ resets the modem\n"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn sim_without_pin_is_configured() {
+    let unit = Unit::start("nopin");
+    let mut sim = unit.simulate(
+        "
+        pin none
+        expect AT+CPOWD=1
+        expect +CLIP=1
+        register 1
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn keypad_pin_opens_door() {
+    let unit = Unit::start("keypad");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32499999999
+        expect AT+DDET=1
+        dtmf 12*4711#
+        expect ATH
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn withheld_callers_can_use_the_keypad() {
+    let unit = Unit::start("keypad-withheld");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        withheld
+        expect AT+DDET=1
+        dtmf 4711#
+        expect ATH
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+}
+
+#[test]
+fn callers_hear_the_outcome() {
+    let unit = Unit::with_config("feedback", "[feedback]\naccepted = \"1,2,3\"\n");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        expect ATA
+        expect AT+VTS=\"1,2,3\"
+        expect ATH
+        ring 32499999999
+        expect ATA
+        expect AT+VTS=\"9,9,9\"
+        expect ATH
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+}
+
+#[test]
+fn unknown_numbers_stay_out_of_the_log() {
+    let unit = Unit::with_config("privacy", "[keypad]\n[privacy]\nunknown = \"redact\"\n");
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32499999999
+        expect AT+DDET=1
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    loop {
+        let line = unit.log.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(!line.contains("32499999999"), "Number in the log: {}", line);
+        if line.contains("Answering for keypad entry") {
+            assert!(line.contains("redacted"));
+            break;
+        }
+    }
+}
+
+#[test]
+fn events_are_kept_while_the_broker_is_unreachable() {
+    // Nothing listens on port 1
+    let unit = Unit::with_config(
+        "outbox",
+        "[mqtt]\n\
+         enable = true\n\
+         server = \"127.0.0.1\"\n\
+         port = 1\n\
+         client_id = \"zuul\"\n\
+         [outbox]\n\
+         path = \"$DIR/outbox.json\"\n",
+    );
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+    // The event is queued right after the log line
+    let outbox = unit.wait_for_file("outbox.json", Duration::from_secs(5), |outbox| {
+        outbox.contains("zuul/open")
+    });
+    assert!(outbox.contains("zuul/ring"));
+    assert!(outbox.contains("zuul/open"));
+}
+
+#[test]
+fn scripts_are_run_for_the_events_they_ask_for() {
+    let unit = Unit::with_config(
+        "exec",
+        "[[notify.exec]]\n\
+         program = \"/bin/sh\"\n\
+         args = [\"-c\", \"echo $ZUUL_EVENT $ZUUL_LABEL >> $DIR/events\"]\n\
+         events = [\"open\"]\n",
+    );
+    let mut sim = unit.simulate(
+        "
+        expect +CPIN=1111
+        register 1
+        ring 32470000001
+        ",
+    );
+    assert!(sim.wait().unwrap().success());
+    assert!(unit.wait_for_log("Opening door", Duration::from_secs(10)));
+    let events = unit.wait_for_file("events", Duration::from_secs(5), |events| {
+        !events.is_empty()
+    });
+    assert_eq!(events, "open Alice\n");
+}
+
+#[test]
+fn daemon_exits_when_the_modem_connection_closes() {
+    let dir = std::env::temp_dir().join(format!("zuul-tcp-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("whitelist"), WHITELIST).unwrap();
+    let ser2net = TcpListener::bind("127.0.0.1:0").unwrap();
+    fs::write(
+        dir.join("config.toml"),
+        format!(
+            "[sockets]\ncontrol = {:?}\n[modem]\ntransport = \"tcp\"\naddress = \"{}\"\n",
+            dir.join("control"),
+            ser2net.local_addr().unwrap()
+        ),
+    )
+    .unwrap();
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_clairvoyant"))
+        .arg("--no-gpio")
+        .arg("-w")
+        .arg(dir.join("whitelist"))
+        .arg("-c")
+        .arg(dir.join("config.toml"))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let (connection, _) = ser2net.accept().unwrap();
+    drop(connection);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = daemon.try_wait().unwrap() {
+            break Some(status);
+        }
+        if Instant::now() > deadline {
+            daemon.kill().ok();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    fs::remove_dir_all(&dir).ok();
+    assert!(!status.expect("Daemon kept running").success());
+}